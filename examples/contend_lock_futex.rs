@@ -5,9 +5,8 @@ extern crate criterion;
 #[macro_use]
 extern crate syscall;
 
-extern crate sleepfast;
 extern crate dontshare;
-extern crate weakrand;
+extern crate stacklock;
 
 use criterion::Criterion;
 
@@ -15,15 +14,13 @@ mod contend;
 
 use contend::{TestCase, contend};
 use dontshare::DontShare;
+use stacklock::{Backoff, Exponential, FutexParker};
 use std::marker::PhantomData;
 use std::mem;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::thread;
 
-const INITIAL_LOOPS: usize = 20;
-const NUM_LOOPS: usize = 20;
-const MAX_EXP: usize = 8;
+const BACKOFF: Exponential = Exponential::new(20, 8);
 
 const UNLOCKED: u32 = 0;
 const LOCKED: u32 = 1;
@@ -68,23 +65,13 @@ impl RawMutex {
                     return guard;
                 }
 
-                if counter > INITIAL_LOOPS {
+                if counter >= BACKOFF.spins() {
                     break;
                 }
 
-                thread::yield_now();
-
-                let exp = if counter < MAX_EXP {
-                    1 << counter
-                } else {
-                    1 << MAX_EXP
-                };
+                BACKOFF.backoff::<FutexParker>(counter);
 
                 counter = counter.wrapping_add(1);
-
-                let spins = weakrand::rand(1, exp);
-
-                sleepfast::pause_times(spins as usize);
             }
         }
 
@@ -106,23 +93,13 @@ impl RawMutex {
                     break 'big_loop;
                 }
 
-                if counter > NUM_LOOPS {
+                if counter >= BACKOFF.spins() {
                     break;
                 }
 
-                thread::yield_now();
-
-                let exp = if counter < MAX_EXP {
-                    1 << counter
-                } else {
-                    1 << MAX_EXP
-                };
+                BACKOFF.backoff::<FutexParker>(counter);
 
                 counter = counter.wrapping_add(1);
-
-                let spins = weakrand::rand(1, exp);
-
-                sleepfast::pause_times(spins as usize);
             }
         }
 
@@ -1,24 +1,24 @@
 #![feature(asm)]
 #![feature(integer_atomics)]
 extern crate criterion;
-extern crate sleepfast;
 extern crate dontshare;
-extern crate weakrand;
+extern crate stacklock;
 
 mod contend;
 
 use dontshare::DontShare;
 
 use criterion::Criterion;
+use stacklock::{Backoff, Exponential, FutexParker};
 use std::marker::PhantomData;
 use std::sync::Arc;
 use std::sync::atomic;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::thread;
 
 use contend::{TestCase, contend};
 
-const MAX_EXP: usize = 9;
+// Never gives up and sleeps
+const BACKOFF: Exponential = Exponential::new(0, 9);
 
 struct Hle {
     val: DontShare<AtomicU32>,
@@ -54,16 +54,8 @@ impl Hle {
 
         let mut counter = 0;
         loop {
-            thread::yield_now();
-            let exp;
-            if counter > MAX_EXP {
-                exp = 1 << MAX_EXP;
-            } else {
-                exp = 1 << counter;
-                counter = counter.wrapping_add(1);
-            }
-
-            sleepfast::pause_times(weakrand::rand(1, exp) as usize);
+            BACKOFF.backoff::<FutexParker>(counter);
+            counter = counter.wrapping_add(1);
 
             if self.val.load(Ordering::Relaxed) == 0 {
                 let mut prev: u32 = 1;
@@ -0,0 +1,75 @@
+extern crate criterion;
+extern crate libc;
+extern crate sleepfast;
+extern crate stacklock;
+
+mod contend;
+
+use stacklock::{Hybrid, Mutex};
+
+use criterion::Criterion;
+use std::marker::PhantomData;
+use std::env;
+use std::sync::Arc;
+
+use contend::{TestCase, contend};
+
+// Long enough that a holder is often descheduled in the middle
+const CRITICAL_SECTION_PAUSES: usize = 200;
+
+enum Adaptive {}
+
+impl TestCase for Adaptive {
+    type TestType = Arc<Mutex<()>>;
+
+    fn create_value() -> Self::TestType {
+        Arc::new(Mutex::with_raw_lock(Hybrid::adaptive(), ()))
+    }
+    fn do_stuff_with_value(value: &Self::TestType, times: usize) {
+        critical_sections(value, times);
+    }
+}
+
+enum NonAdaptive {}
+
+impl TestCase for NonAdaptive {
+    type TestType = Arc<Mutex<()>>;
+
+    fn create_value() -> Self::TestType {
+        Arc::new(Mutex::with_raw_lock(Hybrid::new(), ()))
+    }
+    fn do_stuff_with_value(value: &Self::TestType, times: usize) {
+        critical_sections(value, times);
+    }
+}
+
+fn critical_sections(value: &Arc<Mutex<()>>, times: usize) {
+    let borrowed = &*value;
+    for _ in 0..times {
+        let _guard = borrowed.lock().unwrap();
+        sleepfast::pause_times(CRITICAL_SECTION_PAUSES);
+    }
+}
+
+// Run several times more threads than there are CPUs so that lock
+// holders keep getting preempted.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut num_threads: Vec<usize> =
+        args.iter().skip(1).map(|s| s.parse::<usize>().unwrap()).collect();
+
+    if num_threads.is_empty() {
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } as usize;
+        num_threads = vec![2 * cpus, 4 * cpus, 8 * cpus];
+    }
+
+    let adaptive: PhantomData<Adaptive> = PhantomData;
+    let non_adaptive: PhantomData<NonAdaptive> = PhantomData;
+    let mut criterion = Criterion::default();
+    criterion.bench_function_over_inputs("contend_lock_oversubscribed_adaptive",
+                                         |b, &&n| contend(adaptive, |f| b.iter(f), n),
+                                         num_threads.iter());
+    criterion.bench_function_over_inputs("contend_lock_oversubscribed_non_adaptive",
+                                         |b, &&n| contend(non_adaptive, |f| b.iter(f), n),
+                                         num_threads.iter());
+}
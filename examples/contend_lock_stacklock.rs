@@ -23,7 +23,7 @@ impl TestCase for MyTestCase {
     fn do_stuff_with_value(value: &Self::TestType, times: usize) {
         let borrowed = &*value;
         for _ in 0..times {
-            let _ = borrowed.lock();
+            let _ = borrowed.lock().unwrap();
         }
     }
 }
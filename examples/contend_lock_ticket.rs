@@ -5,6 +5,7 @@ extern crate syscall;
 extern crate sleepfast;
 extern crate criterion;
 extern crate dontshare;
+extern crate stacklock;
 extern crate weakrand;
 
 mod contend;
@@ -12,19 +13,42 @@ mod contend;
 use criterion::Criterion;
 
 use dontshare::DontShare;
+use stacklock::{Backoff, FutexParker, Parker};
 
 use std::mem;
 use std::marker::PhantomData;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::thread;
 
 use contend::{TestCase, contend};
 
-const NUM_LOOPS: usize = 30;
 const MAX_EXP: usize = 9;
 const YIELD_INTERVAL: usize = 8;
 
+// Spin but only yield every few tries
+#[derive(Clone, Copy)]
+struct TicketBackoff;
+
+impl Backoff for TicketBackoff {
+    const INIT: Self = TicketBackoff;
+
+    fn spins(&self) -> usize {
+        30
+    }
+
+    fn backoff<P: Parker>(&self, attempt: usize) {
+        if attempt % YIELD_INTERVAL == YIELD_INTERVAL - 1 {
+            P::yield_now();
+        }
+        let exp = if attempt > MAX_EXP {
+            1 << MAX_EXP
+        } else {
+            1 << attempt
+        };
+        sleepfast::pause_times(weakrand::rand(1, exp) as usize);
+    }
+}
+
 const FUTEX_WAIT_BITSET_PRIVATE: usize = 9 | 128;
 const FUTEX_WAKE_BITSET_PRIVATE: usize = 10 | 128;
 
@@ -63,18 +87,10 @@ impl Ticket {
                         ticket: my_ticket,
                     };
                 }
-                if counter >= NUM_LOOPS {
+                if counter >= TicketBackoff.spins() {
                     break;
                 }
-                if counter % YIELD_INTERVAL == YIELD_INTERVAL - 1 {
-                    thread::yield_now();
-                }
-                let exp = if counter > MAX_EXP {
-                    1 << MAX_EXP
-                } else {
-                    1 << counter
-                };
-                sleepfast::pause_times(weakrand::rand(1, exp) as usize);
+                TicketBackoff.backoff::<FutexParker>(counter);
                 counter = counter.wrapping_add(1);
             }
 
@@ -0,0 +1,167 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use stack::Head;
+
+/// A pointer to a node packed together with a lock bit and a tag
+/// that is bumped on every change so a weak compare and swap can't
+/// be fooled by a node that was popped and pushed back again.
+///
+/// On 64 bit targets nodes must be aligned to 128 bytes and their
+/// addresses must fit in 48 bits.  The tag is only 22 bits and so can
+/// wrap.  The `wide_aba` feature lifts both limits.  On 32 bit
+/// targets the whole pointer fits next to a 31 bit tag.
+pub struct Aba<N> {
+    ptr: u64,
+    _phantom: PhantomData<*mut N>,
+}
+impl<N> Clone for Aba<N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<N> Copy for Aba<N> {}
+
+// Because of pointer alignment to 128 bytes 7 end bits are always zero.
+#[cfg(target_pointer_width = "64")]
+const ZERO_BITS: u64 = 7;
+#[cfg(target_pointer_width = "32")]
+const ZERO_BITS: u64 = 0;
+
+const LOCKED_OFFSET: u64 = 0;
+const LOCKED_SIZE: u64 = 1;
+
+const TAG_OFFSET: u64 = LOCKED_SIZE;
+#[cfg(target_pointer_width = "64")]
+const TAG_SIZE: u64 = 22;
+#[cfg(target_pointer_width = "32")]
+const TAG_SIZE: u64 = 31;
+
+const PTR_OFFSET: u64 = LOCKED_SIZE + TAG_SIZE;
+#[cfg(target_pointer_width = "64")]
+const PTR_SIZE: u64 = 41;
+#[cfg(target_pointer_width = "32")]
+const PTR_SIZE: u64 = 32;
+
+impl<N> Aba<N> {
+    /// An empty unlocked stack, for static initializers.
+    #[inline]
+    pub const fn null() -> Self {
+        Aba {
+            ptr: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn new(node: *mut N, tag: u32, locked: bool) -> Self {
+        let lock_bit: u64 = if locked { 1 } else { 0 };
+        let node_bits = node as usize as u64;
+        let tag_bits: u64 = (tag & ((1 << TAG_SIZE) - 1)) as u64;
+        Aba {
+            ptr: lock_bit << LOCKED_OFFSET | tag_bits << TAG_OFFSET |
+                 (node_bits >> ZERO_BITS) << PTR_OFFSET,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn from_bits(ptr: u64) -> Self {
+        Aba {
+            ptr,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn get(&self, offset: u64, size: u64) -> u64 {
+        (self.ptr >> offset) & ((1 << size) - 1)
+    }
+
+    pub fn locked(&self) -> bool {
+        self.get(LOCKED_OFFSET, LOCKED_SIZE) != 0
+    }
+
+    pub fn ptr(&self) -> *mut N {
+        let node_bits = self.get(PTR_OFFSET, PTR_SIZE) << ZERO_BITS;
+        node_bits as usize as *mut N
+    }
+    pub fn tag(&self) -> u32 {
+        let tag_bits = self.get(TAG_OFFSET, TAG_SIZE);
+        tag_bits as u32
+    }
+}
+impl<N> PartialEq for Aba<N> {
+    fn eq(&self, other: &Aba<N>) -> bool {
+        self.ptr == other.ptr
+    }
+}
+pub struct AtomicAba<N> {
+    ptr: AtomicU64,
+    _phantom: PhantomData<*mut N>,
+}
+impl<N> AtomicAba<N> {
+    #[inline]
+    pub const fn new(ptr: Aba<N>) -> Self {
+        AtomicAba {
+            ptr: AtomicU64::new(ptr.ptr),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn load(&self, ordering: Ordering) -> Aba<N> {
+        Aba::from_bits(self.ptr.load(ordering))
+    }
+
+    pub fn compare_exchange_weak(&self,
+                                 old: Aba<N>,
+                                 new: Aba<N>,
+                                 success: Ordering,
+                                 fail: Ordering)
+                                 -> Result<Aba<N>, Aba<N>> {
+        // Test and test and set optimization
+        let mut dblcheck = self.ptr.load(fail);
+        if dblcheck == old.ptr {
+            match self.ptr
+                .compare_exchange_weak(old.ptr, new.ptr, success, fail) {
+                Err(x) => dblcheck = x,
+                Ok(x) => return Ok(Aba::from_bits(x)),
+            }
+        }
+        Err(Aba::from_bits(dblcheck))
+    }
+}
+
+impl<N> Head for AtomicAba<N> {
+    type Node = N;
+    type Word = Aba<N>;
+
+    fn load(&self) -> Aba<N> {
+        AtomicAba::load(self, Ordering::Relaxed)
+    }
+
+    fn top(word: Aba<N>) -> *mut N {
+        word.ptr()
+    }
+
+    fn locked(word: Aba<N>) -> bool {
+        word.locked()
+    }
+
+    fn replace(&self, old: Aba<N>, top: *mut N, locked: bool) -> Result<(), Aba<N>> {
+        let new = Aba::new(top, old.tag().wrapping_add(1), locked);
+        self.compare_exchange_weak(old, new, Ordering::SeqCst, Ordering::Relaxed)
+            .map(|_| ())
+    }
+}
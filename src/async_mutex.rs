@@ -0,0 +1,303 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::cell::UnsafeCell;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use dontshare::DontShare;
+
+use aba::{Aba, AtomicAba};
+use stack::{DEFAULT_MAX_BYPASS, Link, Stack};
+
+/// A mutex for async code.  Waiting tasks are never blocked on a
+/// futex but push a node holding their `Waker` onto a Treiber stack
+/// the same way the stack lock does.  Unlocking pops a node and
+/// wakes its task with the lock already handed over to it.
+///
+/// Dropping a `lock` future while it waits abandons its node on the
+/// stack for the unlocker to skip.  If the lock was handed to the
+/// future before it got dropped it is passed straight on.
+///
+/// Like the stack lock no waiter is overtaken by more than 64 later
+/// arrivals.  Unlike `Mutex` it is never poisoned.
+pub struct AsyncMutex<T: ?Sized> {
+    stack: Stack<AtomicAba<Waiter>>,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+pub struct AsyncMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a AsyncMutex<T>,
+    _phantom: PhantomData<&'a mut T>,
+}
+
+/// The future returned by `AsyncMutex::lock`.
+pub struct AsyncLockFuture<'a, T: ?Sized + 'a> {
+    lock: &'a AsyncMutex<T>,
+    waiter: Option<Arc<Waiter>>,
+}
+
+impl<T> AsyncMutex<T> {
+    pub const fn new(val: T) -> Self {
+        AsyncMutex {
+            stack: Stack::new(AtomicAba::new(Aba::null())),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    pub fn lock(&self) -> AsyncLockFuture<'_, T> {
+        AsyncLockFuture {
+            lock: self,
+            waiter: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<'_, T>> {
+        if self.try_acquire() {
+            Some(AsyncMutexGuard::new(self))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.stack.try_lock()
+    }
+
+    /// Either acquire the lock and return false or push the node and
+    /// return true.  There is no backoff here as yielding would hold
+    /// up the executor's other tasks.
+    fn push(&self, node: *mut Waiter) -> bool {
+        self.stack.push(node, || {})
+    }
+
+    fn unlock(&self) {
+        loop {
+            let node = unsafe { self.next_waiter() };
+            if node.is_null() {
+                break;
+            }
+            // Take back the reference the stack held
+            let waiter = unsafe { Arc::from_raw(node) };
+            if waiter.state
+                .compare_exchange(WAITING, SIGNALLED, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok() {
+                waiter.wake();
+                break;
+            }
+            // The future was dropped.  We still hold the lock so
+            // move on to the next one.
+        }
+    }
+
+    /// Take the next waiter to pass the lock to.  If there is none
+    /// release the lock and return null.  Must hold the lock.
+    unsafe fn next_waiter(&self) -> *mut Waiter {
+        self.stack.next_waiter(DEFAULT_MAX_BYPASS, false, || {})
+    }
+}
+
+impl<T: Default> Default for AsyncMutex<T> {
+    fn default() -> AsyncMutex<T> {
+        AsyncMutex::new(Default::default())
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Future for AsyncLockFuture<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncMutexGuard<'a, T>> {
+        let this = self.get_mut();
+        let lock = this.lock;
+
+        if let Some(ref waiter) = this.waiter {
+            waiter.register(cx.waker());
+            // Checked after registering so a handoff that took the
+            // old waker is not missed.
+            if waiter.state.load(Ordering::SeqCst) != SIGNALLED {
+                return Poll::Pending;
+            }
+        } else {
+            if lock.try_acquire() {
+                return Poll::Ready(AsyncMutexGuard::new(lock));
+            }
+
+            let waiter = Arc::new(Waiter::new(cx.waker().clone()));
+            // The stack holds its own reference to the node
+            let node = Arc::into_raw(waiter.clone()) as *mut Waiter;
+            if !lock.push(node) {
+                unsafe {
+                    drop(Arc::from_raw(node));
+                }
+                return Poll::Ready(AsyncMutexGuard::new(lock));
+            }
+            this.waiter = Some(waiter);
+            return Poll::Pending;
+        }
+
+        this.waiter = None;
+        Poll::Ready(AsyncMutexGuard::new(lock))
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncLockFuture<'a, T> {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            if waiter.state
+                .compare_exchange(WAITING, ABANDONED, Ordering::SeqCst, Ordering::Relaxed)
+                .is_err() {
+                // Lost the race against unlock, the lock is ours so
+                // pass it on.
+                self.lock.unlock();
+            }
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> AsyncMutexGuard<'a, T> {
+    fn new(lock: &'a AsyncMutex<T>) -> AsyncMutexGuard<'a, T> {
+        AsyncMutexGuard {
+            lock,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for AsyncMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+const WAITING: u32 = 0;
+const SIGNALLED: u32 = 1;
+const ABANDONED: u32 = 2;
+
+// The waker slot is free, being written by the future or being taken
+// by the unlocker.  Neither side ever waits for the other.
+const WAKER_IDLE: u32 = 0;
+const WAKER_REGISTERING: u32 = 1;
+const WAKER_WAKING: u32 = 2;
+
+// Shared between the future and the stack so that neither has to
+// wait for the other to be done with it.
+struct Waiter {
+    next: DontShare<UnsafeCell<*mut Waiter>>,
+    state: AtomicU32,
+    waker_state: AtomicU32,
+    waker: UnsafeCell<Option<Waker>>,
+}
+unsafe impl Send for Waiter {}
+unsafe impl Sync for Waiter {}
+
+impl Link for Waiter {
+    unsafe fn next(node: *mut Self) -> *mut *mut Self {
+        (*node).next.get()
+    }
+
+    unsafe fn priority(_node: *mut Self) -> u32 {
+        0
+    }
+}
+
+impl Waiter {
+    fn new(waker: Waker) -> Waiter {
+        Waiter {
+            next: DontShare::new(UnsafeCell::new(ptr::null_mut())),
+            state: AtomicU32::new(WAITING),
+            waker_state: AtomicU32::new(WAKER_IDLE),
+            waker: UnsafeCell::new(Some(waker)),
+        }
+    }
+
+    // Only ever called by the future and so never concurrently with
+    // itself.
+    fn register(&self, waker: &Waker) {
+        match self.waker_state
+            .compare_exchange(WAKER_IDLE, WAKER_REGISTERING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe {
+                    let slot = &mut *self.waker.get();
+                    let stale = match *slot {
+                        Some(ref old) => !old.will_wake(waker),
+                        None => true,
+                    };
+                    if stale {
+                        *slot = Some(waker.clone());
+                    }
+                }
+                if self.waker_state
+                    .compare_exchange(WAKER_REGISTERING,
+                                      WAKER_IDLE,
+                                      Ordering::AcqRel,
+                                      Ordering::Acquire)
+                    .is_err() {
+                    // A wake up came in while registering and left
+                    // the waker to us.
+                    let waker = unsafe { (*self.waker.get()).take() };
+                    self.waker_state.store(WAKER_IDLE, Ordering::Release);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(_) => {
+                // Being woken right now, poll again
+                waker.wake_by_ref();
+            }
+        }
+    }
+
+    fn wake(&self) {
+        if self.waker_state.fetch_or(WAKER_WAKING, Ordering::AcqRel) != WAKER_IDLE {
+            // The future is registering and will wake itself
+            return;
+        }
+        let waker = unsafe { (*self.waker.get()).take() };
+        self.waker_state.fetch_and(!WAKER_WAKING, Ordering::Release);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
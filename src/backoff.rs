@@ -0,0 +1,177 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::cmp;
+
+use parker::Parker;
+use sleepfast;
+#[cfg(feature = "std")]
+use weakrand;
+
+/// How a lock waits between attempts to take it.
+///
+/// Every lock in the crate is generic over its policy, with
+/// `Exponential` as the default, and takes one with `with_backoff`.
+/// A policy is a small value copied into the lock and anything that
+/// sleeps or allocates does not belong here.
+pub trait Backoff: Copy {
+    /// The policy locks created without one use.
+    const INIT: Self;
+
+    /// The policy `Hybrid` spins with in front of its queues when
+    /// created without one.  Queueing there is cheap so it can give
+    /// up sooner than the locks underneath.
+    const SPIN_INIT: Self = Self::INIT;
+
+    /// The last attempt after which to back off rather than go to
+    /// sleep.  Locks that can't sleep at that point, such as a thread
+    /// that lost a race to change a stack, retry regardless.
+    fn spins(&self) -> usize;
+
+    /// Wait after failing to take a lock for the `attempt`th time,
+    /// counting from zero.  Yielding goes through the lock's parker
+    /// `P`.
+    fn backoff<P: Parker>(&self, attempt: usize);
+}
+
+/// Yield and then spin for a random number of pauses below a bound
+/// that doubles on every attempt up to `2^max_exp`.
+#[derive(Clone, Copy, Debug)]
+pub struct Exponential {
+    spins: usize,
+    max_exp: usize,
+}
+
+impl Exponential {
+    pub const fn new(spins: usize, max_exp: usize) -> Self {
+        Exponential {
+            spins,
+            max_exp,
+        }
+    }
+}
+
+impl Backoff for Exponential {
+    const INIT: Self = Exponential::new(20, 8);
+    const SPIN_INIT: Self = Exponential::new(10, 8);
+
+    fn spins(&self) -> usize {
+        self.spins
+    }
+
+    fn backoff<P: Parker>(&self, attempt: usize) {
+        P::yield_now();
+        pause_exponential(attempt, self.max_exp);
+    }
+}
+
+/// Yield and then spin for the same number of pauses every time.
+#[derive(Clone, Copy, Debug)]
+pub struct Fixed {
+    spins: usize,
+    pauses: usize,
+}
+
+impl Fixed {
+    pub const fn new(spins: usize, pauses: usize) -> Self {
+        Fixed {
+            spins,
+            pauses,
+        }
+    }
+}
+
+impl Backoff for Fixed {
+    const INIT: Self = Fixed::new(20, 64);
+
+    fn spins(&self) -> usize {
+        self.spins
+    }
+
+    fn backoff<P: Parker>(&self, _attempt: usize) {
+        P::yield_now();
+        sleepfast::pause_times(self.pauses);
+    }
+}
+
+/// Only give up the CPU to other threads, never spin.  Suits
+/// machines with more runnable threads than CPUs.
+#[derive(Clone, Copy, Debug)]
+pub struct YieldOnly {
+    spins: usize,
+}
+
+impl YieldOnly {
+    pub const fn new(spins: usize) -> Self {
+        YieldOnly { spins }
+    }
+}
+
+impl Backoff for YieldOnly {
+    const INIT: Self = YieldOnly::new(20);
+
+    fn spins(&self) -> usize {
+        self.spins
+    }
+
+    fn backoff<P: Parker>(&self, _attempt: usize) {
+        P::yield_now();
+    }
+}
+
+/// Spin like `Exponential` but never yield.  Suits threads that each
+/// have a CPU to themselves.
+#[derive(Clone, Copy, Debug)]
+pub struct SpinOnly {
+    spins: usize,
+    max_exp: usize,
+}
+
+impl SpinOnly {
+    pub const fn new(spins: usize, max_exp: usize) -> Self {
+        SpinOnly {
+            spins,
+            max_exp,
+        }
+    }
+}
+
+impl Backoff for SpinOnly {
+    const INIT: Self = SpinOnly::new(20, 8);
+
+    fn spins(&self) -> usize {
+        self.spins
+    }
+
+    fn backoff<P: Parker>(&self, attempt: usize) {
+        pause_exponential(attempt, self.max_exp);
+    }
+}
+
+fn pause_exponential(attempt: usize, max_exp: usize) {
+    let shift = cmp::min(attempt, max_exp);
+    let exp = if shift < 64 { 1 << shift } else { u64::MAX };
+    sleepfast::pause_times(jitter(exp) as usize);
+}
+
+#[cfg(feature = "std")]
+fn jitter(exp: u64) -> u64 {
+    weakrand::rand(1, exp)
+}
+
+// Without a thread local random number generator every waiter pauses
+// for the whole bound.
+#[cfg(not(feature = "std"))]
+fn jitter(exp: u64) -> u64 {
+    exp
+}
@@ -0,0 +1,157 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::sync::{LockResult, PoisonError};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futex;
+use raw_lock::RawLock;
+use MutexGuard;
+
+/// A condition variable for use with `stacklock::Mutex`.
+///
+/// Waiters sleep on a sequence number.  Instead of waking every
+/// waiter `notify_all` wakes one and requeues the rest onto the
+/// mutex's futex word so that they are let through one at a time as
+/// the mutex is unlocked.  Raw locks without a futex word fall back
+/// to waking everybody.
+pub struct Condvar {
+    seq: AtomicU32,
+    mutex: AtomicUsize,
+    requeue: AtomicUsize,
+}
+
+/// Whether a timed wait on a condition variable timed out.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar {
+            seq: AtomicU32::new(0),
+            mutex: AtomicUsize::new(0),
+            requeue: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn wait<'a, T: ?Sized, R: RawLock>(&self,
+                                           guard: MutexGuard<'a, T, R>)
+                                           -> LockResult<MutexGuard<'a, T, R>> {
+        let (guard, _) = self.wait_until(guard, None);
+        if guard.lock.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub fn wait_timeout<'a, T: ?Sized, R: RawLock>(&self,
+                                                   guard: MutexGuard<'a, T, R>,
+                                                   timeout: Duration)
+                                                   -> LockResult<(MutexGuard<'a, T, R>, WaitTimeoutResult)> {
+        // An overflowing timeout is as good as forever
+        let deadline = Instant::now().checked_add(timeout);
+        let (guard, timed_out) = self.wait_until(guard, deadline);
+        let result = WaitTimeoutResult(timed_out);
+        if guard.lock.is_poisoned() {
+            Err(PoisonError::new((guard, result)))
+        } else {
+            Ok((guard, result))
+        }
+    }
+
+    /// Wait until `condition` returns false.
+    pub fn wait_while<'a, T: ?Sized, R: RawLock, F>(&self,
+                                                    mut guard: MutexGuard<'a, T, R>,
+                                                    mut condition: F)
+                                                    -> LockResult<MutexGuard<'a, T, R>>
+        where F: FnMut(&mut T) -> bool
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        futex::wake(&self.seq, 1);
+    }
+
+    pub fn notify_all(&self) {
+        if self.mutex.load(Ordering::Relaxed) == 0 {
+            // Nobody has ever waited
+            return;
+        }
+        let requeue = self.requeue.load(Ordering::Relaxed);
+        if requeue == 0 {
+            self.seq.fetch_add(1, Ordering::SeqCst);
+            futex::wake(&self.seq, i32::MAX as u32);
+            return;
+        }
+        let target = unsafe { &*(requeue as *const AtomicU32) };
+        let mut seq = self.seq.fetch_add(1, Ordering::SeqCst).wrapping_add(1);
+        // Retry if a concurrent notify bumped the sequence number
+        // under us.
+        while !futex::requeue(&self.seq, seq, 1, i32::MAX, target) {
+            seq = self.seq.load(Ordering::SeqCst);
+        }
+    }
+
+    fn wait_until<'a, T: ?Sized, R: RawLock>(&self,
+                                             guard: MutexGuard<'a, T, R>,
+                                             deadline: Option<Instant>)
+                                             -> (MutexGuard<'a, T, R>, bool) {
+        let mutex = &guard.lock.mutex;
+        self.verify(mutex);
+
+        let seq = self.seq.load(Ordering::SeqCst);
+        #[cfg(feature = "deadlock_detection")]
+        guard.lock.disowned();
+        unsafe {
+            mutex.unlock();
+        }
+        let timed_out = !futex::wait(&self.seq, seq, deadline);
+        mutex.lock_requeued();
+        #[cfg(feature = "deadlock_detection")]
+        guard.lock.owned();
+
+        (guard, timed_out)
+    }
+
+    fn verify<R: RawLock>(&self, mutex: &R) {
+        let addr = mutex as *const R as usize;
+        match self.mutex.compare_exchange(0, addr, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => {
+                if let Some(word) = mutex.futex_word() {
+                    self.requeue.store(word as *const AtomicU32 as usize, Ordering::Relaxed);
+                }
+            }
+            Err(old) if old == addr => {}
+            Err(_) => panic!("attempted to use a condition variable with two mutexes"),
+        }
+    }
+}
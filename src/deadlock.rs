@@ -0,0 +1,242 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+//! Deadlock detection, enabled with the `deadlock_detection`
+//! feature.
+//!
+//! Every `Mutex` records which thread owns it and a thread that has
+//! to wait on a lock registers itself as waiting on it.  A watchdog
+//! thread looks at every thread that has waited for longer than the
+//! threshold and follows the chain of owners: the owner of the lock
+//! it waits on, the lock that owner waits on and so on.  If the chain
+//! leads back to the thread it started at the threads in it are
+//! deadlocked and a report is made once instead of hanging silently.
+//! The threads still go on waiting afterwards.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use raw_lock::RawLock;
+
+/// One of the threads in a deadlock.
+pub struct Waiter {
+    pub thread: Option<String>,
+    pub thread_id: thread::ThreadId,
+    /// The address of the `Mutex` it waits on.
+    pub lock: usize,
+    /// The name the `Mutex` was created with, if any.
+    pub name: Option<&'static str>,
+}
+
+/// A cycle of threads each waiting on a lock held by the next, the
+/// last waiting on a lock held by the first.
+pub struct Report {
+    pub cycle: Vec<Waiter>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadlock detected:")?;
+        for waiter in &self.cycle {
+            write!(f,
+                   "\n  thread {:?} ({}) waits on lock ",
+                   waiter.thread_id,
+                   waiter.thread.as_ref().map(|name| &name[..]).unwrap_or("<unnamed>"))?;
+            match waiter.name {
+                Some(name) => write!(f, "{} ({:#x})", name, waiter.lock)?,
+                None => write!(f, "{:#x}", waiter.lock)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn print_report(report: &Report) {
+    eprintln!("stacklock: {}", report);
+}
+
+static HANDLER: Mutex<fn(&Report)> = Mutex::new(print_report);
+
+/// Replace what is done with reports.  By default they are printed
+/// to standard error.
+pub fn set_report_handler(handler: fn(&Report)) {
+    *HANDLER.lock().unwrap() = handler;
+}
+
+// In milliseconds
+static THRESHOLD: AtomicUsize = AtomicUsize::new(1000);
+
+/// How long a thread has to wait on a lock before the watchdog
+/// checks it for a deadlock.  One second by default.
+pub fn set_threshold(threshold: Duration) {
+    let millis = threshold.as_secs() as usize * 1000 + threshold.subsec_millis() as usize;
+    THRESHOLD.store(millis, Ordering::Relaxed);
+}
+
+// Zero means no owner
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(1);
+
+thread_local!(static TOKEN: usize = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed));
+
+fn token() -> usize {
+    TOKEN.with(|token| *token)
+}
+
+/// The thread holding a lock and the lock's name for reports.
+pub struct Owner {
+    thread: AtomicUsize,
+    name: Option<&'static str>,
+}
+
+impl Owner {
+    pub const fn new(name: Option<&'static str>) -> Owner {
+        Owner {
+            thread: AtomicUsize::new(0),
+            name,
+        }
+    }
+
+    pub fn acquire(&self) {
+        self.thread.store(token(), Ordering::Relaxed);
+    }
+
+    pub fn release(&self) {
+        self.thread.store(0, Ordering::Relaxed);
+    }
+}
+
+struct Waiting {
+    thread: thread::Thread,
+    lock: usize,
+    // Valid as long as the thread is registered as waiting on it
+    owner: *const Owner,
+    since: Instant,
+    reported: bool,
+}
+unsafe impl Send for Waiting {}
+
+static WAITING: Mutex<Option<HashMap<usize, Waiting>>> = Mutex::new(None);
+
+/// Lock with `lock_raw`, letting the watchdog know while waiting.
+/// Returns whether `lock_raw` took the lock.
+pub fn lock<R, F>(raw: &R, lock: usize, owner: &Owner, lock_raw: F) -> bool
+    where R: RawLock,
+          F: FnOnce(&R) -> bool
+{
+    if raw.try_lock() {
+        return true;
+    }
+
+    let me = token();
+    {
+        let mut waiting = WAITING.lock().unwrap();
+        let waiting = waiting.get_or_insert_with(|| {
+            thread::Builder::new()
+                .name("stacklock deadlock watchdog".to_owned())
+                .spawn(watchdog)
+                .unwrap();
+            HashMap::new()
+        });
+        waiting.insert(me,
+                       Waiting {
+                           thread: thread::current(),
+                           lock,
+                           owner,
+                           since: Instant::now(),
+                           reported: false,
+                       });
+    }
+
+    let locked = lock_raw(raw);
+
+    if let Some(ref mut waiting) = *WAITING.lock().unwrap() {
+        waiting.remove(&me);
+    }
+    locked
+}
+
+fn watchdog() {
+    loop {
+        let threshold = Duration::from_millis(THRESHOLD.load(Ordering::Relaxed) as u64);
+        thread::sleep(cmp::max(threshold / 2, Duration::from_millis(1)));
+
+        let mut reports = Vec::new();
+        if let Some(ref mut waiting) = *WAITING.lock().unwrap() {
+            let stuck: Vec<usize> = waiting.iter()
+                .filter(|&(_, waiter)| !waiter.reported && waiter.since.elapsed() >= threshold)
+                .map(|(&thread, _)| thread)
+                .collect();
+            for thread in stuck {
+                if waiting[&thread].reported {
+                    continue;
+                }
+                if let Some(cycle) = find_cycle(waiting, thread) {
+                    let report = Report {
+                        cycle: cycle.iter()
+                            .map(|thread| {
+                                let waiter = &waiting[thread];
+                                Waiter {
+                                    thread: waiter.thread.name().map(|name| name.to_owned()),
+                                    thread_id: waiter.thread.id(),
+                                    lock: waiter.lock,
+                                    // Still registered so the lock is
+                                    // still around
+                                    name: unsafe { (*waiter.owner).name },
+                                }
+                            })
+                            .collect(),
+                    };
+                    for thread in cycle {
+                        waiting.get_mut(&thread).unwrap().reported = true;
+                    }
+                    reports.push(report);
+                }
+            }
+        }
+
+        // Outside of our own lock in case the handler blocks
+        let handler = *HANDLER.lock().unwrap();
+        for report in reports {
+            handler(&report);
+        }
+    }
+}
+
+// Follow the owners from a waiting thread and return the threads
+// passed if they lead back to it.
+fn find_cycle(waiting: &HashMap<usize, Waiting>, start: usize) -> Option<Vec<usize>> {
+    let mut cycle = Vec::new();
+    let mut thread = start;
+    // Every thread can only be passed once unless the chain runs
+    // into a cycle that doesn't go through the start
+    for _ in 0..waiting.len() {
+        cycle.push(thread);
+
+        // Waiters stay registered until they get the lock so the
+        // lock is still around.
+        let owner = waiting[&thread].owner;
+        thread = unsafe { (*owner).thread.load(Ordering::Relaxed) };
+        if thread == start {
+            return Some(cycle);
+        }
+        if !waiting.contains_key(&thread) {
+            return None;
+        }
+    }
+    None
+}
@@ -0,0 +1,162 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use libc;
+
+use std::mem;
+use std::sync::atomic::AtomicU32;
+
+use parker::{time_left, Instant};
+
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+const FUTEX_PRIVATE_FLAG: usize = 128;
+
+const FUTEX_WAIT_PRIVATE: usize = FUTEX_WAIT | FUTEX_PRIVATE_FLAG;
+const FUTEX_WAKE_PRIVATE: usize = FUTEX_WAKE | FUTEX_PRIVATE_FLAG;
+const FUTEX_CMP_REQUEUE_PRIVATE: usize = 4 | FUTEX_PRIVATE_FLAG;
+const FUTEX_LOCK_PI_PRIVATE: usize = 6 | FUTEX_PRIVATE_FLAG;
+const FUTEX_UNLOCK_PI_PRIVATE: usize = 7 | FUTEX_PRIVATE_FLAG;
+
+/// Sleep while `word` still holds `val`.  Returns false only if the
+/// deadline passed, spurious wakeups are possible otherwise.
+pub fn wait(word: &AtomicU32, val: u32, deadline: Option<Instant>) -> bool {
+    wait_op(word, FUTEX_WAIT_PRIVATE, val, deadline)
+}
+
+/// Like `wait` but `word` may be in memory shared with other
+/// processes.
+pub fn wait_shared(word: &AtomicU32, val: u32, deadline: Option<Instant>) -> bool {
+    wait_op(word, FUTEX_WAIT, val, deadline)
+}
+
+fn wait_op(word: &AtomicU32, op: usize, val: u32, deadline: Option<Instant>) -> bool {
+    unsafe {
+        let word_ptr: usize = mem::transmute(word);
+        match deadline {
+            None => {
+                syscall!(FUTEX, word_ptr, op, val, 0);
+                true
+            }
+            Some(deadline) => {
+                let left = match time_left(deadline) {
+                    Some(left) => left,
+                    None => return false,
+                };
+                let timeout = libc::timespec {
+                    tv_sec: left.as_secs() as libc::time_t,
+                    tv_nsec: left.subsec_nanos() as libc::c_long,
+                };
+                let ret = syscall!(FUTEX,
+                                   word_ptr,
+                                   op,
+                                   val,
+                                   &timeout as *const libc::timespec);
+                ret as isize != -(libc::ETIMEDOUT as isize)
+            }
+        }
+    }
+}
+
+/// Wake up to `count` threads sleeping on `word`.  Returns how many
+/// were woken.
+pub fn wake(word: &AtomicU32, count: u32) -> usize {
+    wake_op(word, FUTEX_WAKE_PRIVATE, count)
+}
+
+/// Like `wake` but `word` may be in memory shared with other
+/// processes.
+pub fn wake_shared(word: &AtomicU32, count: u32) -> usize {
+    wake_op(word, FUTEX_WAKE, count)
+}
+
+fn wake_op(word: &AtomicU32, op: usize, count: u32) -> usize {
+    unsafe {
+        let word_ptr: usize = mem::transmute(word);
+        syscall!(FUTEX, word_ptr, op, count)
+    }
+}
+
+/// Wake up to `wake` threads sleeping on `word` and move up to
+/// `requeue` of the rest over to sleep on `target` instead.  Returns
+/// false and does nothing if `word` no longer holds `val`.
+pub fn requeue(word: &AtomicU32, val: u32, wake: i32, requeue: i32, target: &AtomicU32) -> bool {
+    unsafe {
+        let word_ptr: usize = mem::transmute(word);
+        let target_ptr: usize = mem::transmute(target);
+        let ret = syscall!(FUTEX,
+                           word_ptr,
+                           FUTEX_CMP_REQUEUE_PRIVATE,
+                           wake,
+                           requeue,
+                           target_ptr,
+                           val);
+        ret as isize != -(libc::EAGAIN as isize)
+    }
+}
+
+/// Sleep until the kernel hands us the priority inheritance lock
+/// `word`, lending our priority to its owner meanwhile.  Returns
+/// `Ok(false)` if the deadline passed and the error number if the
+/// kernel refused, say with `EDEADLK` if we already own the lock.
+pub fn lock_pi(word: &AtomicU32, deadline: Option<Instant>) -> Result<bool, i32> {
+    unsafe {
+        let word_ptr: usize = mem::transmute(word);
+        loop {
+            let ret = match deadline {
+                None => syscall!(FUTEX, word_ptr, FUTEX_LOCK_PI_PRIVATE, 0, 0),
+                Some(deadline) => {
+                    let left = match time_left(deadline) {
+                        Some(left) => left,
+                        None => return Ok(false),
+                    };
+                    // The timeout is absolute and against the real
+                    // time clock.
+                    let mut timeout: libc::timespec = mem::zeroed();
+                    libc::clock_gettime(libc::CLOCK_REALTIME, &mut timeout);
+                    timeout.tv_sec += left.as_secs() as libc::time_t;
+                    timeout.tv_nsec += left.subsec_nanos() as libc::c_long;
+                    if timeout.tv_nsec >= 1000000000 {
+                        timeout.tv_sec += 1;
+                        timeout.tv_nsec -= 1000000000;
+                    }
+                    syscall!(FUTEX,
+                             word_ptr,
+                             FUTEX_LOCK_PI_PRIVATE,
+                             0,
+                             &timeout as *const libc::timespec)
+                }
+            };
+            match -(ret as isize) as i32 {
+                0 => return Ok(true),
+                libc::ETIMEDOUT => return Ok(false),
+                // The owner is exiting, try again
+                libc::EAGAIN | libc::EINTR => {}
+                err => return Err(err),
+            }
+        }
+    }
+}
+
+/// Release the priority inheritance lock `word` to the highest
+/// priority waiter.  Fails with `EPERM` unless we own the lock.
+pub fn unlock_pi(word: &AtomicU32) -> Result<(), i32> {
+    unsafe {
+        let word_ptr: usize = mem::transmute(word);
+        let ret = syscall!(FUTEX, word_ptr, FUTEX_UNLOCK_PI_PRIVATE);
+        match -(ret as isize) as i32 {
+            0 => Ok(()),
+            err => Err(err),
+        }
+    }
+}
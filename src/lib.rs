@@ -12,86 +12,473 @@
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
 //
-#![feature(asm)]
-#![feature(const_fn)]
-#![feature(integer_atomics)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// Without std the core library stands in for it
+#[cfg(not(feature = "std"))]
+extern crate core as std;
 
 #[macro_use]
 extern crate syscall;
 
 extern crate libc;
 
+extern crate lock_api;
+
 extern crate sleepfast;
 
 extern crate dontshare;
+#[cfg(feature = "std")]
 extern crate weakrand;
 
+#[cfg(not(feature = "wide_aba"))]
+mod aba;
+#[cfg(feature = "wide_aba")]
+#[path = "wide_aba.rs"]
+mod aba;
+#[cfg(feature = "std")]
+mod async_mutex;
+mod backoff;
+#[cfg(feature = "std")]
+mod condvar;
+#[cfg(feature = "deadlock_detection")]
+pub mod deadlock;
+#[cfg(target_os = "linux")]
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+mod futex;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+#[cfg(feature = "node_arena")]
+mod node_arena;
+mod parker;
+#[cfg(feature = "std")]
+mod pi_mutex;
+mod plain_mutex;
+mod poison;
+#[cfg(feature = "profile")]
+pub mod profile;
+mod raw_lock;
+#[cfg(feature = "std")]
 mod raw_mutex;
+#[cfg(feature = "std")]
+mod raw_rwlock;
+#[cfg(feature = "std")]
+mod robust_mutex;
+#[cfg(feature = "std")]
+mod rwlock;
+#[cfg(feature = "std")]
+mod shared_mutex;
+mod stack;
 mod stack_mutex;
+mod stats;
 mod tts_mutex;
 
 use std::cell::UnsafeCell;
 use std::marker::PhantomData;
+use std::mem;
 use std::ops::{Deref, DerefMut};
+#[cfg(any(feature = "lockdep", feature = "profile"))]
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use poison::{LockResult, PoisonError, TryLockError, TryLockResult};
 
-use raw_mutex::RawMutex;
+#[cfg(feature = "std")]
+pub use async_mutex::{AsyncLockFuture, AsyncMutex, AsyncMutexGuard};
+pub use backoff::{Backoff, Exponential, Fixed, SpinOnly, YieldOnly};
+#[cfg(feature = "std")]
+pub use condvar::{Condvar, WaitTimeoutResult};
+pub use parker::{DefaultParker, Instant as Deadline, Parker};
+#[cfg(target_os = "linux")]
+pub use parker::Futex as FutexParker;
+pub use parker::Spin as SpinParker;
+#[cfg(feature = "std")]
+pub use pi_mutex::RawMutex as PiMutex;
+pub use plain_mutex::{PlainMutex, PlainMutexGuard};
+#[cfg(not(feature = "std"))]
+pub use poison::{LockResult, PoisonError, TryLockError, TryLockResult};
+#[cfg(feature = "node_arena")]
+pub use node_arena::{slots as arena_slots, waiters as arena_waiters};
+pub use raw_lock::RawLock;
+#[cfg(feature = "std")]
+pub use raw_mutex::RawMutex;
+#[cfg(feature = "std")]
+pub use raw_mutex::RawMutex as Hybrid;
+#[cfg(feature = "std")]
+pub use shared_mutex::RawMutex as SharedMutex;
+pub use stack_mutex::RawMutex as StackMutex;
+#[cfg(feature = "stats")]
+pub use stats::{Histogram, Stats};
+pub use tts_mutex::RawMutex as FutexMutex;
+#[cfg(feature = "std")]
+pub use robust_mutex::{RobustLockError, RobustLockResult, RobustMutex, RobustMutexGuard};
+#[cfg(feature = "std")]
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-pub struct Mutex<T: ?Sized> {
-    mutex: RawMutex,
+// The raw lock a `Mutex` uses unless told otherwise
+#[cfg(feature = "std")]
+type DefaultLock = Hybrid;
+#[cfg(not(feature = "std"))]
+type DefaultLock = StackMutex;
+
+/// A mutual exclusion lock.  Like the standard library's mutex it is
+/// poisoned if a thread panics while holding it.  Callers that do not
+/// care can recover the guard with `PoisonError::into_inner` or use a
+/// `PlainMutex` instead.
+///
+/// The raw lock underneath defaults to the `Hybrid` lock but any of
+/// the other `RawLock` backends can be picked instead.  Most backends
+/// also take a `Backoff` policy for how waiters spin and a `Parker`
+/// for how they sleep.
+///
+/// Without the default `std` feature the crate is `no_std`.  Then
+/// only the stack lock and the futex lock are built and the stack
+/// lock is the default.  There are no timeouts and as panics can't be
+/// detected a lock is never poisoned.
+///
+/// With the `lockdep` feature every lock checks that locks are
+/// always taken in a consistent order, see the `lockdep` module.
+/// With the `deadlock_detection` feature threads stuck waiting on
+/// each other are reported, see the `deadlock` module.  With the
+/// `stats` feature every lock counts what happens to it, see
+/// `Mutex::stats`.  With the `profile` feature waits are added up
+/// by where locks were created so the most contended ones can be
+/// found, see the `profile` module.
+pub struct Mutex<T: ?Sized, R: RawLock = DefaultLock> {
+    mutex: R,
+    poison: AtomicBool,
+    #[cfg(feature = "lockdep")]
+    class: lockdep::Class,
+    #[cfg(feature = "deadlock_detection")]
+    owner: deadlock::Owner,
+    times: stats::Times,
+    #[cfg(feature = "profile")]
+    profile: profile::Class,
     data: UnsafeCell<T>,
 }
-unsafe impl<T: Send> Send for Mutex<T> {}
-unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send, R: RawLock + Send> Send for Mutex<T, R> {}
+unsafe impl<T: ?Sized + Send, R: RawLock + Sync> Sync for Mutex<T, R> {}
 
-pub struct MutexGuard<'r, T: ?Sized + 'r> {
-    lock: &'r Mutex<T>,
+pub struct MutexGuard<'r, T: ?Sized + 'r, R: RawLock + 'r = DefaultLock> {
+    lock: &'r Mutex<T, R>,
+    panicking: bool,
+    held: stats::Timer,
     _phantom: PhantomData<&'r mut T>,
+    // Keeps guards of locks that must be unlocked by the thread that
+    // locked them on that thread
+    _marker: PhantomData<R::GuardMarker>,
 }
 
 impl<T> Mutex<T> {
-    pub fn new(val: T) -> Self {
+    /// Create an unlocked mutex.  Nothing is allocated or set up at
+    /// run time so it can initialize a `static`.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub const fn new(val: T) -> Self {
+        Mutex::with_raw_lock(DefaultLock::new(), val)
+    }
+
+    /// Create a mutex that the `profile` and `deadlock_detection`
+    /// features report under `name`.  Without them the name is
+    /// ignored.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub const fn with_name(name: &'static str, val: T) -> Self {
+        Mutex::build(DefaultLock::new(), Some(name), val)
+    }
+}
+
+impl<T, R: RawLock> Mutex<T, R> {
+    /// Create a mutex on top of a particular raw lock.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub const fn with_raw_lock(mutex: R, val: T) -> Self {
+        Mutex::build(mutex, None, val)
+    }
+
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    #[cfg_attr(not(any(feature = "profile", feature = "deadlock_detection")),
+               allow(unused_variables))]
+    const fn build(mutex: R, name: Option<&'static str>, val: T) -> Self {
         Mutex {
-            mutex: RawMutex::new(),
+            mutex,
+            poison: AtomicBool::new(false),
+            #[cfg(feature = "lockdep")]
+            class: lockdep::Class::new(),
+            #[cfg(feature = "deadlock_detection")]
+            owner: deadlock::Owner::new(name),
+            times: stats::Times::new(),
+            #[cfg(feature = "profile")]
+            profile: profile::Class::new(name),
             data: UnsafeCell::new(val),
         }
     }
 
-    pub fn into_inner(self) -> T {
-        unsafe { self.data.into_inner() }
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let data = self.data.into_inner();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
     }
 }
 
-impl<T: ?Sized> Mutex<T> {
-    pub fn lock(&self) -> MutexGuard<T> {
-        self.mutex.lock();
-        MutexGuard {
-            lock: self,
-            _phantom: PhantomData,
+impl<T: ?Sized, R: RawLock> Mutex<T, R> {
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T, R>> {
+        self.check_order();
+        let waiting = stats::Timer::start();
+        self.lock_raw(|raw| raw.lock());
+        self.times.waited(&waiting);
+        MutexGuard::new(self)
+    }
+
+    /// Lock ahead of any waiters with a lower priority.  A plain
+    /// `lock` has the lowest priority of zero.  Only the stack lock
+    /// and `Hybrid` order their waiters, other backends just lock.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub fn lock_with_priority(&self, priority: u32) -> LockResult<MutexGuard<'_, T, R>> {
+        self.check_order();
+        let waiting = stats::Timer::start();
+        self.lock_raw(|raw| raw.lock_with_priority(priority));
+        self.times.waited(&waiting);
+        MutexGuard::new(self)
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T, R>> {
+        if !self.mutex.try_lock() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(MutexGuard::new(self)?)
+    }
+
+    /// Try to acquire the lock, giving up after `timeout` has
+    /// elapsed.
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<MutexGuard<'_, T, R>> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => Ok(self.lock()?),
+        }
+    }
+
+    /// Try to acquire the lock, giving up once `deadline` has
+    /// passed.
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock_until(&self, deadline: Instant) -> TryLockResult<MutexGuard<'_, T, R>> {
+        self.check_order();
+        let waiting = stats::Timer::start();
+        if !self.wait_raw(|raw| raw.try_lock_until(deadline)) {
+            return Err(TryLockError::WouldBlock);
+        }
+        self.times.waited(&waiting);
+        Ok(MutexGuard::new(self)?)
+    }
+
+    /// Whether a thread panicked while holding the lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.load(Ordering::Relaxed)
+    }
+
+    /// Mark the data as consistent again after a panic.
+    pub fn clear_poison(&self) {
+        self.poison.store(false, Ordering::Relaxed);
+    }
+
+    /// What has happened to the lock so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        self.times.add_to(&mut stats);
+        self.mutex.add_stats(&mut stats);
+        stats
+    }
+
+    // Locks shared between processes are left out of lock order
+    // checking as their classes name places in one process's image.
+    #[cfg(feature = "lockdep")]
+    #[track_caller]
+    fn check_order(&self) {
+        if !R::PROCESS_SHARED {
+            lockdep::acquire(&self.class, Location::caller());
+        }
+    }
+
+    #[cfg(not(feature = "lockdep"))]
+    fn check_order(&self) {}
+
+    #[cfg(feature = "lockdep")]
+    #[track_caller]
+    fn held(&self) {
+        if !R::PROCESS_SHARED {
+            lockdep::acquired(&self.class, Location::caller());
+        }
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn released(&self) {
+        if !R::PROCESS_SHARED {
+            lockdep::release(&self.class);
+        }
+    }
+
+    // Locks shared between processes are left alone as the profile
+    // lives in the memory of one process.
+    #[cfg(feature = "profile")]
+    #[track_caller]
+    fn lock_raw<F: FnOnce(&R)>(&self, lock_raw: F) {
+        if R::PROCESS_SHARED {
+            self.wait_raw(|raw| {
+                lock_raw(raw);
+                true
+            });
+            return;
+        }
+        let start = Instant::now();
+        self.wait_raw(|raw| {
+            lock_raw(raw);
+            true
+        });
+        self.profile.locked(Location::caller(), start.elapsed());
+    }
+
+    #[cfg(not(feature = "profile"))]
+    fn lock_raw<F: FnOnce(&R)>(&self, lock_raw: F) {
+        self.wait_raw(|raw| {
+            lock_raw(raw);
+            true
+        });
+    }
+
+    // Shared locks are likewise left out of deadlock detection as
+    // owners are told apart by tokens local to one process.
+    #[cfg(feature = "deadlock_detection")]
+    fn wait_raw<F: FnOnce(&R) -> bool>(&self, lock_raw: F) -> bool {
+        if R::PROCESS_SHARED {
+            return lock_raw(&self.mutex);
+        }
+        let addr = self as *const Self as *const () as usize;
+        deadlock::lock(&self.mutex, addr, &self.owner, lock_raw)
+    }
+
+    #[cfg(not(feature = "deadlock_detection"))]
+    fn wait_raw<F: FnOnce(&R) -> bool>(&self, lock_raw: F) -> bool {
+        lock_raw(&self.mutex)
+    }
+
+    #[cfg(feature = "deadlock_detection")]
+    fn owned(&self) {
+        if !R::PROCESS_SHARED {
+            self.owner.acquire();
+        }
+    }
+
+    #[cfg(feature = "deadlock_detection")]
+    fn disowned(&self) {
+        if !R::PROCESS_SHARED {
+            self.owner.release();
         }
     }
 }
 
-impl<T: ?Sized + Default> Default for Mutex<T> {
-    fn default() -> Mutex<T> {
-        Mutex::new(Default::default())
+impl<T: Default, R: RawLock> Default for Mutex<T, R> {
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    fn default() -> Mutex<T, R> {
+        Mutex::with_raw_lock(R::INIT, Default::default())
     }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for MutexGuard<'a, T> {
+impl<'r, T: ?Sized + 'r, R: RawLock + 'r> MutexGuard<'r, T, R> {
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    fn new(lock: &'r Mutex<T, R>) -> LockResult<MutexGuard<'r, T, R>> {
+        #[cfg(feature = "lockdep")]
+        lock.held();
+        #[cfg(feature = "deadlock_detection")]
+        lock.owned();
+
+        let guard = MutexGuard {
+            lock,
+            panicking: poison::panicking(),
+            held: stats::Timer::start(),
+            _phantom: PhantomData,
+            _marker: PhantomData,
+        };
+        if lock.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Unlock without letting newly arriving threads barge in ahead
+    /// of threads already waiting, if there are any.  See
+    /// `RawLock::unlock_fair` for which waiter gets the lock.
+    pub fn unlock_fair(this: Self) {
+        this.poison();
+        #[cfg(feature = "lockdep")]
+        this.lock.released();
+        #[cfg(feature = "deadlock_detection")]
+        this.lock.disowned();
+        this.lock.times.held(&this.held);
+        unsafe {
+            this.lock.mutex.unlock_fair();
+        }
+        mem::forget(this);
+    }
+
+    /// Give a waiting thread a turn with the lock and then take it
+    /// back.
+    pub fn bump(this: &mut Self) {
+        this.poison();
+        #[cfg(feature = "deadlock_detection")]
+        this.lock.disowned();
+        this.lock.wait_raw(|raw| {
+            unsafe {
+                raw.bump();
+            }
+            true
+        });
+        #[cfg(feature = "deadlock_detection")]
+        this.lock.owned();
+        this.panicking = poison::panicking();
+    }
+
+    // Only a panic that started while the lock was held poisons it.
+    fn poison(&self) {
+        if !self.panicking && poison::panicking() {
+            self.lock.poison.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawLock + 'a> Deref for MutexGuard<'a, T, R> {
     type Target = T;
     fn deref(&self) -> &T {
         unsafe { &*self.lock.data.get() }
     }
 }
 
-impl<'a, T: ?Sized + 'a> DerefMut for MutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a, R: RawLock + 'a> DerefMut for MutexGuard<'a, T, R> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.lock.data.get() }
     }
 }
 
-impl<'r, T: ?Sized + 'r> Drop for MutexGuard<'r, T> {
+impl<'r, T: ?Sized + 'r, R: RawLock + 'r> Drop for MutexGuard<'r, T, R> {
     fn drop(&mut self) {
-        self.lock.mutex.unlock();
+        self.poison();
+        #[cfg(feature = "lockdep")]
+        self.lock.released();
+        #[cfg(feature = "deadlock_detection")]
+        self.lock.disowned();
+        self.lock.times.held(&self.held);
+        unsafe {
+            self.lock.mutex.unlock();
+        }
     }
 }
@@ -0,0 +1,206 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+//! Lock order checking, enabled with the `lockdep` feature.
+//!
+//! Every `Mutex` belongs to the lock class of the place in the source
+//! it was created at, so all the locks one constructor makes share a
+//! class and the graph stays as small as the program.  Whenever a
+//! thread locks one while already holding others an edge from each
+//! held class to the new one is added to a global graph.  If the new
+//! lock could already be followed by one of the held locks in the
+//! graph then some two threads could deadlock by taking them in
+//! opposite orders.  That is reported the first time the bad order
+//! is seen, whether or not it ever actually deadlocks.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::panic::Location;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicPtr, Ordering};
+
+pub type Site = &'static Location<'static>;
+
+/// A potential deadlock.  The lock taken at `held` was held while
+/// the lock taken at `acquired` was being taken.  Earlier on the
+/// same two locks were used the other way around, the lock taken at
+/// `previous_held` was held while the lock taken at
+/// `previous_acquired` was being taken, possibly through a chain of
+/// other locks in between.
+pub struct Report {
+    pub held: Site,
+    pub acquired: Site,
+    pub previous_held: Site,
+    pub previous_acquired: Site,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "possible lock order inversion: lock taken at {} while holding lock taken at {}, \
+                but earlier lock taken at {} while holding lock taken at {}",
+               self.acquired,
+               self.held,
+               self.previous_acquired,
+               self.previous_held)
+    }
+}
+
+fn print_report(report: &Report) {
+    eprintln!("stacklock: {}", report);
+}
+
+static HANDLER: Mutex<fn(&Report)> = Mutex::new(print_report);
+
+/// Replace what is done with reports.  By default they are printed
+/// to standard error.
+pub fn set_report_handler(handler: fn(&Report)) {
+    *HANDLER.lock().unwrap() = handler;
+}
+
+/// The lock class of a `Mutex`, named by where it was created.
+pub struct Class {
+    site: Site,
+    // The held locks of the thread holding it.  A guard may be
+    // dropped on another thread than the one that took it.
+    holder: AtomicPtr<Held>,
+}
+
+impl Default for Class {
+    #[track_caller]
+    fn default() -> Class {
+        Class::new()
+    }
+}
+
+impl Class {
+    #[track_caller]
+    pub const fn new() -> Class {
+        Class {
+            site: Location::caller(),
+            holder: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+struct Graph {
+    // The sites where each order was first seen
+    edges: HashMap<(Site, Site), (Site, Site)>,
+    after: HashMap<Site, Vec<Site>>,
+    reported: HashSet<(Site, Site)>,
+}
+
+static GRAPH: Mutex<Option<Graph>> = Mutex::new(None);
+
+// The locks held by address together with their class and where they
+// were taken
+type Held = Mutex<Vec<(usize, Site, Site)>>;
+
+thread_local!(static HELD: Arc<Held> = Arc::new(Mutex::new(Vec::new())));
+
+/// Check the order before blocking on the lock.
+pub fn acquire(class: &Class, site: Site) {
+    let id = class.site;
+    let mut reports = Vec::new();
+    HELD.with(|held| {
+        let held = held.lock().unwrap();
+        if held.is_empty() {
+            return;
+        }
+
+        let mut graph = GRAPH.lock().unwrap();
+        let graph = graph.get_or_insert_with(|| {
+            Graph {
+                edges: HashMap::new(),
+                after: HashMap::new(),
+                reported: HashSet::new(),
+            }
+        });
+        for &(_, held_id, held_site) in held.iter() {
+            if held_id == id || graph.edges.contains_key(&(held_id, id)) {
+                continue;
+            }
+            if let Some(first) = graph.path(id, held_id) {
+                if graph.reported.insert((held_id, id)) {
+                    let (previous_held, previous_acquired) = graph.edges[&first];
+                    reports.push(Report {
+                        held: held_site,
+                        acquired: site,
+                        previous_held,
+                        previous_acquired,
+                    });
+                }
+                continue;
+            }
+            graph.edges.insert((held_id, id), (held_site, site));
+            graph.after.entry(held_id).or_default().push(id);
+        }
+    });
+
+    // Outside of our own locks in case the handler panics
+    if !reports.is_empty() {
+        let handler = *HANDLER.lock().unwrap();
+        for report in reports {
+            handler(&report);
+        }
+    }
+}
+
+/// Note the lock as held once it has been taken.
+pub fn acquired(class: &Class, site: Site) {
+    let addr = class as *const Class as usize;
+    HELD.with(|held| {
+        held.lock().unwrap().push((addr, class.site, site));
+        let holder = Arc::into_raw(held.clone()) as *mut Held;
+        class.holder.store(holder, Ordering::Relaxed);
+    });
+}
+
+/// Remove the lock from the held locks of the thread that took it,
+/// which need not be the current one.
+pub fn release(class: &Class) {
+    let addr = class as *const Class as usize;
+    let holder = class.holder.swap(ptr::null_mut(), Ordering::Relaxed);
+    if holder.is_null() {
+        return;
+    }
+    let held = unsafe { Arc::from_raw(holder) };
+    let mut held = held.lock().unwrap();
+    if let Some(pos) = held.iter().rposition(|&(held_addr, _, _)| held_addr == addr) {
+        held.remove(pos);
+    }
+}
+
+impl Graph {
+    // Find the first edge of a path from `from` to `to`
+    fn path(&self, from: Site, to: Site) -> Option<(Site, Site)> {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<(Site, (Site, Site))> = Vec::new();
+        for &next in self.after.get(&from).into_iter().flat_map(|after| after.iter()) {
+            stack.push((next, (from, next)));
+        }
+        while let Some((node, first)) = stack.pop() {
+            if node == to {
+                return Some(first);
+            }
+            if !seen.insert(node) {
+                continue;
+            }
+            for &next in self.after.get(&node).into_iter().flat_map(|after| after.iter()) {
+                stack.push((next, first));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+
+//! A process wide arena of stack lock nodes, enabled with the
+//! `node_arena` feature.
+//!
+//! Normally a waiter pushes a node on its own stack and the head of
+//! a `StackMutex` packs the node's address, which only works for 128
+//! byte aligned nodes at addresses that fit in 48 bits.  With the
+//! arena every thread owns a slot that it builds its node in instead
+//! and the head packs the slot's index.  A 20 bit index leaves room
+//! for a 43 bit tag in a plain 64 bit compare and swap wherever the
+//! arena is mapped.  Timed waits take a slot of their own as they
+//! may have to abandon it on the stack.
+//!
+//! Slots are never unmapped, only put back for reuse when their
+//! thread exits, so a stale index always names some node.  `slots`
+//! and `waiters`, exported as `arena_slots` and `arena_waiters`, let
+//! tools see how many there are and how many are in use.
+
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use stack::Head;
+
+// Room for the node of a stack lock with a backoff policy of a few
+// words.
+const NODE_SIZE: usize = 512;
+
+const LOCKED_OFFSET: u64 = 0;
+const LOCKED_SIZE: u64 = 1;
+
+const TAG_OFFSET: u64 = LOCKED_SIZE;
+const TAG_SIZE: u64 = 43;
+
+const INDEX_OFFSET: u64 = LOCKED_SIZE + TAG_SIZE;
+const INDEX_SIZE: u64 = 20;
+
+// Index zero is the null node
+const MAX_SLOTS: usize = (1 << INDEX_SIZE) - 1;
+
+// Chunk `n` holds `FIRST_CHUNK << n` slots so that growing the arena
+// never moves a slot.
+const FIRST_CHUNK: usize = 64;
+const NUM_CHUNKS: usize = 15;
+
+#[repr(C, align(128))]
+struct Slot {
+    node: UnsafeCell<[u8; NODE_SIZE]>,
+    index: u32,
+    busy: AtomicBool,
+}
+
+struct Arena {
+    len: usize,
+    free: Vec<u32>,
+}
+
+static ARENA: Mutex<Arena> = Mutex::new(Arena {
+    len: 0,
+    free: Vec::new(),
+});
+
+static CHUNKS: [AtomicPtr<Slot>; NUM_CHUNKS] = [const { AtomicPtr::new(ptr::null_mut()) };
+                                                 NUM_CHUNKS];
+
+// The slot the current thread waits in, zero until it first needs
+// one.  It is put back when the thread exits.
+struct Local(Cell<u32>);
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        let index = self.0.get();
+        if index != 0 {
+            release(index);
+        }
+    }
+}
+
+thread_local!(static LOCAL: Local = const { Local(Cell::new(0)) });
+
+/// The number of slots handed out so far, one for every thread that
+/// ever waited on a stack lock plus a few for timed waits.
+pub fn slots() -> usize {
+    ARENA.lock().unwrap().len
+}
+
+/// The number of slots holding the node of a thread that is in the
+/// middle of locking a stack lock or a node abandoned by a timed out
+/// waiter that is still on a stack.
+pub fn waiters() -> usize {
+    let len = slots();
+    (1..len + 1)
+        .filter(|&index| slot(index as u32).busy.load(Ordering::Relaxed))
+        .count()
+}
+
+/// Build `node` in the calling thread's own slot and pass it to `f`.
+/// The node must be off every stack by the time `f` returns.
+pub fn with_local<N, R, F: FnOnce(*mut N) -> R>(node: N, f: F) -> R {
+    let index = LOCAL.try_with(|local| {
+        if local.0.get() == 0 {
+            local.0.set(take());
+        }
+        local.0.get()
+    });
+    match index {
+        Ok(index) => {
+            let node = init(index, node);
+            let result = f(node);
+            slot(index).busy.store(false, Ordering::Relaxed);
+            result
+        }
+        Err(_) => {
+            // The thread is exiting and its slot is gone so borrow one
+            let node = alloc(node);
+            let result = f(node);
+            unsafe {
+                free(node);
+            }
+            result
+        }
+    }
+}
+
+/// Build `node` in a slot of its own that stays taken until `free`.
+pub fn alloc<N>(node: N) -> *mut N {
+    init(take(), node)
+}
+
+/// Put back the slot of a node from `alloc`.
+pub unsafe fn free<N>(node: *mut N) {
+    let slot = &*(node as *const Slot);
+    slot.busy.store(false, Ordering::Relaxed);
+    release(slot.index);
+}
+
+fn init<N>(index: u32, node: N) -> *mut N {
+    // Checked when building rather than on every lock
+    const {
+        assert!(mem::size_of::<N>() <= NODE_SIZE && mem::align_of::<N>() <= 128,
+                "stack lock nodes must fit in an arena slot");
+    }
+
+    let slot = slot(index);
+    slot.busy.store(true, Ordering::Relaxed);
+    let ptr = slot.node.get() as *mut N;
+    unsafe {
+        ptr::write(ptr, node);
+    }
+    ptr
+}
+
+fn take() -> u32 {
+    let mut arena = ARENA.lock().unwrap();
+    if let Some(index) = arena.free.pop() {
+        return index;
+    }
+
+    let offset = arena.len;
+    assert!(offset < MAX_SLOTS, "the node arena is full");
+    let (chunk, start) = chunk_of(offset);
+    if offset == start {
+        let slots: Vec<Slot> = (0..FIRST_CHUNK << chunk)
+            .map(|n| {
+                Slot {
+                    node: UnsafeCell::new([0; NODE_SIZE]),
+                    index: (start + n + 1) as u32,
+                    busy: AtomicBool::new(false),
+                }
+            })
+            .collect();
+        let slots = Box::into_raw(slots.into_boxed_slice()) as *mut Slot;
+        CHUNKS[chunk].store(slots, Ordering::Release);
+    }
+    arena.len = offset + 1;
+    (offset + 1) as u32
+}
+
+fn release(index: u32) {
+    ARENA.lock().unwrap().free.push(index);
+}
+
+// The chunk an offset into the arena falls in and the offset that
+// chunk starts at.
+fn chunk_of(offset: usize) -> (usize, usize) {
+    let chunk = 31 - ((offset / FIRST_CHUNK + 1) as u32).leading_zeros() as usize;
+    (chunk, FIRST_CHUNK * ((1 << chunk) - 1))
+}
+
+fn slot(index: u32) -> &'static Slot {
+    let offset = index as usize - 1;
+    let (chunk, start) = chunk_of(offset);
+    unsafe { &*CHUNKS[chunk].load(Ordering::Acquire).add(offset - start) }
+}
+
+/// The index of a node's slot packed together with a lock bit and a
+/// tag that is bumped on every change, a drop in replacement for the
+/// `aba` module's pointers.  Nodes must come from this arena.
+pub struct Aba<N> {
+    bits: u64,
+    _phantom: PhantomData<*mut N>,
+}
+impl<N> Clone for Aba<N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<N> Copy for Aba<N> {}
+
+impl<N> Aba<N> {
+    /// An empty unlocked stack, for static initializers.
+    #[inline]
+    pub const fn null() -> Self {
+        Aba {
+            bits: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Unsafe as the slot's index is read through `node`, which must
+    /// be null or come from this arena.
+    #[inline]
+    pub unsafe fn new(node: *mut N, tag: u64, locked: bool) -> Self {
+        let lock_bit: u64 = if locked { 1 } else { 0 };
+        let index = if node.is_null() {
+            0
+        } else {
+            (*(node as *const Slot)).index as u64
+        };
+        let tag_bits = tag & ((1 << TAG_SIZE) - 1);
+        Aba {
+            bits: lock_bit << LOCKED_OFFSET | tag_bits << TAG_OFFSET | index << INDEX_OFFSET,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn from_bits(bits: u64) -> Self {
+        Aba {
+            bits,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn get(&self, offset: u64, size: u64) -> u64 {
+        (self.bits >> offset) & ((1 << size) - 1)
+    }
+
+    pub fn locked(&self) -> bool {
+        self.get(LOCKED_OFFSET, LOCKED_SIZE) != 0
+    }
+
+    pub fn ptr(&self) -> *mut N {
+        let index = self.get(INDEX_OFFSET, INDEX_SIZE) as u32;
+        if index == 0 {
+            return ptr::null_mut();
+        }
+        slot(index).node.get() as *mut N
+    }
+
+    pub fn tag(&self) -> u64 {
+        self.get(TAG_OFFSET, TAG_SIZE)
+    }
+}
+impl<N> PartialEq for Aba<N> {
+    fn eq(&self, other: &Aba<N>) -> bool {
+        self.bits == other.bits
+    }
+}
+
+pub struct AtomicAba<N> {
+    bits: AtomicU64,
+    _phantom: PhantomData<*mut N>,
+}
+impl<N> AtomicAba<N> {
+    #[inline]
+    pub const fn new(aba: Aba<N>) -> Self {
+        AtomicAba {
+            bits: AtomicU64::new(aba.bits),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn load(&self, ordering: Ordering) -> Aba<N> {
+        Aba::from_bits(self.bits.load(ordering))
+    }
+
+    pub fn compare_exchange_weak(&self,
+                                 old: Aba<N>,
+                                 new: Aba<N>,
+                                 success: Ordering,
+                                 fail: Ordering)
+                                 -> Result<Aba<N>, Aba<N>> {
+        // Test and test and set optimization
+        let mut dblcheck = self.bits.load(fail);
+        if dblcheck == old.bits {
+            match self.bits
+                .compare_exchange_weak(old.bits, new.bits, success, fail) {
+                Err(x) => dblcheck = x,
+                Ok(x) => return Ok(Aba::from_bits(x)),
+            }
+        }
+        Err(Aba::from_bits(dblcheck))
+    }
+}
+
+impl<N> Head for AtomicAba<N> {
+    type Node = N;
+    type Word = Aba<N>;
+
+    fn load(&self) -> Aba<N> {
+        AtomicAba::load(self, Ordering::Relaxed)
+    }
+
+    fn top(word: Aba<N>) -> *mut N {
+        word.ptr()
+    }
+
+    fn locked(word: Aba<N>) -> bool {
+        word.locked()
+    }
+
+    // Only ever passed nodes the lock built in the arena
+    fn replace(&self, old: Aba<N>, top: *mut N, locked: bool) -> Result<(), Aba<N>> {
+        let new = unsafe { Aba::new(top, old.tag().wrapping_add(1), locked) };
+        self.compare_exchange_weak(old, new, Ordering::SeqCst, Ordering::Relaxed)
+            .map(|_| ())
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+//! How waiting threads go to sleep and are woken up again.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use futex;
+#[cfg(target_os = "linux")]
+use libc;
+use sleepfast;
+
+/// When a wait gives up.  This is the standard library's `Instant`
+/// but without `std` there is no clock.  Then it is an empty type,
+/// nothing can time out and waits only end by being woken.
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Instant {}
+
+/// How a lock puts waiting threads to sleep and wakes them up.
+///
+/// The futex lock, the stack lock and `Hybrid` are generic over their
+/// parker with `DefaultParker` as the default.  A parker is never
+/// created, its functions are called on the type and so it is best
+/// implemented on an empty enum.  Environments with a wait primitive
+/// other than a futex can plug it in here.
+pub trait Parker {
+    /// Whether waiters sleep on a private futex.  Only then can a
+    /// condition variable have the kernel requeue its waiters onto
+    /// the lock.
+    const FUTEX: bool = false;
+
+    /// Sleep while `word` still holds `val`.  Returns false only if
+    /// the deadline passed, spurious wakeups are possible otherwise.
+    fn wait(word: &AtomicU32, val: u32, deadline: Option<Instant>) -> bool;
+
+    /// Wake up to `count` threads sleeping on `word`.  Returns how
+    /// many were woken or `count` if that can't be known.
+    fn wake(word: &AtomicU32, count: u32) -> usize;
+
+    /// Let other threads run.
+    fn yield_now();
+
+    /// The CPU the calling thread is running on.  Only used to spread
+    /// waiters out so it may be stale or even always zero.
+    fn current_cpu() -> usize;
+}
+
+/// Sleep on a Linux futex.  Works without `std` as well although
+/// then there are no timeouts.
+#[cfg(target_os = "linux")]
+pub enum Futex {}
+
+#[cfg(target_os = "linux")]
+impl Parker for Futex {
+    const FUTEX: bool = true;
+
+    fn wait(word: &AtomicU32, val: u32, deadline: Option<Instant>) -> bool {
+        futex::wait(word, val, deadline)
+    }
+
+    fn wake(word: &AtomicU32, count: u32) -> usize {
+        futex::wake(word, count)
+    }
+
+    fn yield_now() {
+        unsafe {
+            syscall!(SCHED_YIELD);
+        }
+    }
+
+    fn current_cpu() -> usize {
+        unsafe { libc::sched_getcpu() as usize }
+    }
+}
+
+/// Never sleep, just spin until the word changes.  The fallback for
+/// when there is nothing to wait on.  Waiters keep their CPU busy and
+/// can keep the lock holder from running so this only suits threads
+/// that each have a CPU to themselves.
+pub enum Spin {}
+
+impl Parker for Spin {
+    fn wait(word: &AtomicU32, val: u32, deadline: Option<Instant>) -> bool {
+        while word.load(Ordering::Relaxed) == val {
+            if let Some(deadline) = deadline {
+                if time_left(deadline).is_none() {
+                    return false;
+                }
+            }
+            sleepfast::pause_times(1);
+        }
+        true
+    }
+
+    fn wake(_word: &AtomicU32, count: u32) -> usize {
+        count as usize
+    }
+
+    fn yield_now() {
+        sleepfast::pause_times(1);
+    }
+
+    fn current_cpu() -> usize {
+        0
+    }
+}
+
+/// The parker locks use unless given another, futexes on Linux and
+/// spinning elsewhere.
+#[cfg(target_os = "linux")]
+pub type DefaultParker = Futex;
+
+#[cfg(not(target_os = "linux"))]
+pub type DefaultParker = Spin;
+
+/// How long until `deadline` or `None` once it has passed.
+#[cfg(feature = "std")]
+pub fn time_left(deadline: Instant) -> Option<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        None
+    } else {
+        Some(deadline - now)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub fn time_left(deadline: Instant) -> Option<Duration> {
+    match deadline {}
+}
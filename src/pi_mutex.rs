@@ -0,0 +1,143 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use libc;
+use lock_api;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use futex;
+use raw_lock::RawLock;
+
+const FUTEX_WAITERS: u32 = 0x80000000;
+
+/// A priority inheritance lock.  The futex word holds the owner's
+/// thread id so that when a thread has to wait the kernel can find
+/// the owner and boost it to the waiter's priority until it unlocks.
+/// A low priority holder can then no longer be held up indefinitely
+/// by medium priority threads while a high priority one waits.
+///
+/// The uncontended paths stay in userspace.  Under contention the
+/// kernel queues waiters by priority and hands the lock over
+/// directly so the default fair unlock is the same as a normal one.
+///
+/// Only the thread that locked it can unlock it as the kernel will
+/// not release a lock on behalf of another thread.  Guards of a
+/// `Mutex` built on it are not `Send` for that reason.
+pub struct RawMutex {
+    word: AtomicU32,
+}
+
+impl Default for RawMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawMutex {
+    #[inline]
+    pub const fn new() -> RawMutex {
+        RawMutex { word: AtomicU32::new(0) }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.word.load(Ordering::Relaxed) != 0
+    }
+
+    pub fn try_lock(&self) -> bool {
+        if self.word.load(Ordering::Relaxed) != 0 {
+            return false;
+        }
+        self.word
+            .compare_exchange(0, gettid(), Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    pub fn lock(&self) {
+        if self.try_lock() {
+            return;
+        }
+        loop {
+            match futex::lock_pi(&self.word, None) {
+                Ok(_) => return,
+                // The kernel could not allocate the state it keeps for
+                // a contended lock, try again later
+                Err(libc::ENOMEM) => thread::yield_now(),
+                Err(err) => lock_failed(err),
+            }
+        }
+    }
+
+    /// Like lock but gives up once the deadline passes.
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        if self.try_lock() {
+            return true;
+        }
+        match futex::lock_pi(&self.word, Some(deadline)) {
+            Ok(locked) => locked,
+            Err(libc::ENOMEM) => false,
+            Err(err) => lock_failed(err),
+        }
+    }
+
+    /// Must only be called by the thread that locked it.
+    pub fn unlock(&self) {
+        let val = self.word.load(Ordering::Relaxed);
+        if val & FUTEX_WAITERS == 0 &&
+           self.word
+            .compare_exchange(val, 0, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok() {
+            return;
+        }
+        // The kernel only refuses with EPERM when called by a thread
+        // other than the owner
+        let _ = futex::unlock_pi(&self.word);
+    }
+}
+
+fn lock_failed(err: i32) -> ! {
+    if err == libc::EDEADLK {
+        panic!("PiMutex locked again by the thread that owns it");
+    }
+    panic!("FUTEX_LOCK_PI failed: {}", err)
+}
+
+thread_local!(static THREAD_ID: u32 = unsafe { syscall!(GETTID) as u32 });
+
+fn gettid() -> u32 {
+    THREAD_ID.with(|id| *id)
+}
+
+unsafe impl RawLock for RawMutex {
+    const INIT: RawMutex = RawMutex::new();
+
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use poison::{PoisonError, TryLockError};
+use raw_lock::RawLock;
+use {DefaultLock, Mutex, MutexGuard};
+
+/// A `Mutex` without poisoning, for code that has no use for
+/// `LockResult`.  A thread that panics while holding the lock just
+/// unlocks it and the next one locks as usual.  Otherwise it is the
+/// same as a `Mutex` on the same raw lock.
+pub struct PlainMutex<T: ?Sized, R: RawLock = DefaultLock> {
+    mutex: Mutex<T, R>,
+}
+
+pub struct PlainMutexGuard<'r, T: ?Sized + 'r, R: RawLock + 'r = DefaultLock> {
+    guard: MutexGuard<'r, T, R>,
+}
+
+impl<T> PlainMutex<T> {
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub const fn new(val: T) -> Self {
+        PlainMutex { mutex: Mutex::new(val) }
+    }
+
+    /// Create a mutex that the `profile` and `deadlock_detection`
+    /// features report under `name`.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub const fn with_name(name: &'static str, val: T) -> Self {
+        PlainMutex { mutex: Mutex::with_name(name, val) }
+    }
+}
+
+impl<T, R: RawLock> PlainMutex<T, R> {
+    /// Create a mutex on top of a particular raw lock.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub const fn with_raw_lock(mutex: R, val: T) -> Self {
+        PlainMutex { mutex: Mutex::with_raw_lock(mutex, val) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.mutex.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: ?Sized, R: RawLock> PlainMutex<T, R> {
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub fn lock(&self) -> PlainMutexGuard<'_, T, R> {
+        PlainMutexGuard::new(self.mutex.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Lock ahead of any waiters with a lower priority, see
+    /// `Mutex::lock_with_priority`.
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    pub fn lock_with_priority(&self, priority: u32) -> PlainMutexGuard<'_, T, R> {
+        let guard = self.mutex.lock_with_priority(priority);
+        PlainMutexGuard::new(guard.unwrap_or_else(PoisonError::into_inner))
+    }
+
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock(&self) -> Option<PlainMutexGuard<'_, T, R>> {
+        PlainMutexGuard::from_try(self.mutex.try_lock())
+    }
+
+    /// Try to acquire the lock, giving up after `timeout` has
+    /// elapsed.
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<PlainMutexGuard<'_, T, R>> {
+        PlainMutexGuard::from_try(self.mutex.try_lock_for(timeout))
+    }
+
+    /// Try to acquire the lock, giving up once `deadline` has
+    /// passed.
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "lockdep", track_caller)]
+    pub fn try_lock_until(&self, deadline: Instant) -> Option<PlainMutexGuard<'_, T, R>> {
+        PlainMutexGuard::from_try(self.mutex.try_lock_until(deadline))
+    }
+}
+
+impl<T: Default, R: RawLock> Default for PlainMutex<T, R> {
+    #[cfg_attr(any(feature = "lockdep", feature = "profile"), track_caller)]
+    fn default() -> PlainMutex<T, R> {
+        PlainMutex { mutex: Mutex::default() }
+    }
+}
+
+impl<'r, T: ?Sized + 'r, R: RawLock + 'r> PlainMutexGuard<'r, T, R> {
+    fn new(guard: MutexGuard<'r, T, R>) -> Self {
+        PlainMutexGuard { guard }
+    }
+
+    fn from_try(result: Result<MutexGuard<'r, T, R>, TryLockError<MutexGuard<'r, T, R>>>)
+                -> Option<Self> {
+        match result {
+            Ok(guard) => Some(PlainMutexGuard::new(guard)),
+            Err(TryLockError::Poisoned(err)) => Some(PlainMutexGuard::new(err.into_inner())),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    /// Unlock and hand the lock directly to a waiting thread, see
+    /// `MutexGuard::unlock_fair`.
+    pub fn unlock_fair(this: Self) {
+        MutexGuard::unlock_fair(this.guard);
+    }
+
+    /// Give a waiting thread a turn with the lock and then take it
+    /// back.
+    pub fn bump(this: &mut Self) {
+        MutexGuard::bump(&mut this.guard);
+    }
+}
+
+impl<'r, T: ?Sized + 'r, R: RawLock + 'r> Deref for PlainMutexGuard<'r, T, R> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'r, T: ?Sized + 'r, R: RawLock + 'r> DerefMut for PlainMutexGuard<'r, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+//! Lock poisoning.  With `std` these are the standard library's
+//! types.  Without it there are stand ins of the same shape but a
+//! panic can't be detected and so nothing is ever poisoned.
+
+#[cfg(not(feature = "std"))]
+use std::fmt;
+
+#[cfg(feature = "std")]
+pub use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+
+#[cfg(feature = "std")]
+pub fn panicking() -> bool {
+    ::std::thread::panicking()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn panicking() -> bool {
+    false
+}
+
+/// A lock was poisoned.  Holds the guard anyway.
+#[cfg(not(feature = "std"))]
+pub struct PoisonError<T> {
+    guard: T,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> PoisonError<T> {
+    pub fn new(guard: T) -> PoisonError<T> {
+        PoisonError { guard }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.guard
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.guard
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Debug for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PoisonError { .. }")
+    }
+}
+
+/// Why a `try_lock` failed.
+#[cfg(not(feature = "std"))]
+pub enum TryLockError<T> {
+    Poisoned(PoisonError<T>),
+    WouldBlock,
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> From<PoisonError<T>> for TryLockError<T> {
+    fn from(err: PoisonError<T>) -> TryLockError<T> {
+        TryLockError::Poisoned(err)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T> fmt::Debug for TryLockError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TryLockError::Poisoned(ref err) => err.fmt(f),
+            TryLockError::WouldBlock => f.write_str("WouldBlock"),
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+#[cfg(not(feature = "std"))]
+pub type TryLockResult<Guard> = Result<Guard, TryLockError<Guard>>;
@@ -0,0 +1,296 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+//! A contention profiler, enabled with the `profile` feature.
+//!
+//! Locks are profiled by lock class: all the `Mutex`es created at the
+//! same place in the source under the same name, the one given to
+//! `Mutex::with_name` if there is one.  Every lock is timed and a
+//! wait that took longer than an uncontended lock ever should is
+//! added up for the class as a whole and for the place it was locked
+//! from.  `report` then lists the classes that were waited on the
+//! longest.
+//!
+//! A class is registered the first time one of its locks is locked
+//! and never removed so locks that are gone still show up in
+//! reports.  Nothing is kept in the lock itself but its name and
+//! where it was created.  Locks that can be shared between processes,
+//! such as `SharedMutex`, are not profiled at all.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::panic::Location;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub type Site = &'static Location<'static>;
+
+// Shorter waits are taken to be uncontended locks
+const CONTENDED: Duration = Duration::from_micros(1);
+
+type Key = (Site, Option<&'static str>);
+
+static REGISTRY: Mutex<Option<HashMap<Key, &'static Entry>>> = Mutex::new(None);
+
+// Entries already looked up by this thread, by address to keep the
+// lookups cheap
+thread_local!(static ENTRIES: RefCell<HashMap<(usize, usize), &'static Entry>> =
+                  RefCell::new(HashMap::new()));
+
+/// The lock class of a `Mutex`, named by where it was created and
+/// its name.
+pub struct Class {
+    name: Option<&'static str>,
+    created: Site,
+}
+
+impl Class {
+    #[track_caller]
+    pub const fn new(name: Option<&'static str>) -> Class {
+        Class {
+            name,
+            created: Location::caller(),
+        }
+    }
+
+    /// Record a lock from `site` that waited for `waited`.
+    pub fn locked(&self, site: Site, waited: Duration) {
+        self.entry().locked(site, waited);
+    }
+
+    fn entry(&self) -> &'static Entry {
+        let addr = (self.created as *const Location as usize,
+                    self.name.map(|name| name.as_ptr() as usize).unwrap_or(0));
+        // The cache may be gone in a thread local's destructor
+        ENTRIES.try_with(|entries| {
+                *entries.borrow_mut()
+                    .entry(addr)
+                    .or_insert_with(|| self.register())
+            })
+            .unwrap_or_else(|_| self.register())
+    }
+
+    fn register(&self) -> &'static Entry {
+        let mut registry = REGISTRY.lock().unwrap();
+        let entry = registry.get_or_insert_with(HashMap::new)
+            .entry((self.created, self.name))
+            .or_insert_with(|| {
+                Box::leak(Box::new(Entry {
+                    name: self.name,
+                    created: self.created,
+                    locks: AtomicU64::new(0),
+                    contended: AtomicU64::new(0),
+                    wait_nanos: AtomicU64::new(0),
+                    max_wait_nanos: AtomicU64::new(0),
+                    callers: Mutex::new(HashMap::new()),
+                }))
+            });
+        entry
+    }
+}
+
+struct Entry {
+    name: Option<&'static str>,
+    created: Site,
+    locks: AtomicU64,
+    contended: AtomicU64,
+    wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+    // How often and how long each site waited
+    callers: Mutex<HashMap<Site, (u64, u64)>>,
+}
+
+impl Entry {
+    fn locked(&self, site: Site, waited: Duration) {
+        self.locks.fetch_add(1, Ordering::Relaxed);
+        if waited < CONTENDED {
+            return;
+        }
+
+        let nanos = nanos(waited);
+        self.contended.fetch_add(1, Ordering::Relaxed);
+        self.wait_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_wait_nanos.fetch_max(nanos, Ordering::Relaxed);
+
+        let mut callers = self.callers.lock().unwrap();
+        let caller = callers.entry(site).or_insert((0, 0));
+        caller.0 += 1;
+        caller.1 += nanos;
+    }
+}
+
+/// The locks waited on the longest first.
+pub struct Report {
+    pub locks: Vec<LockReport>,
+}
+
+/// What the locks of one class went through.
+pub struct LockReport {
+    /// The name given to `Mutex::with_name`.
+    pub name: Option<&'static str>,
+    /// Where the locks were created.
+    pub created: Site,
+    /// How many times they were locked.
+    pub locks: u64,
+    /// How many times they had to be waited for.
+    pub contended: u64,
+    pub wait_time: Duration,
+    pub max_wait_time: Duration,
+    /// The places it was waited for from, longest total wait first.
+    pub callers: Vec<CallerReport>,
+}
+
+pub struct CallerReport {
+    pub site: Site,
+    pub contended: u64,
+    pub wait_time: Duration,
+}
+
+/// Collect what every lock class registered so far went through.
+pub fn report() -> Report {
+    let entries: Vec<&'static Entry> = match *REGISTRY.lock().unwrap() {
+        Some(ref registry) => registry.values().cloned().collect(),
+        None => Vec::new(),
+    };
+    let mut locks: Vec<LockReport> = entries.iter()
+        .map(|entry| {
+            let mut callers: Vec<CallerReport> = entry.callers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&site, &(contended, nanos))| {
+                    CallerReport {
+                        site,
+                        contended,
+                        wait_time: Duration::from_nanos(nanos),
+                    }
+                })
+                .collect();
+            callers.sort_by_key(|caller| cmp::Reverse(caller.wait_time));
+            LockReport {
+                name: entry.name,
+                created: entry.created,
+                locks: entry.locks.load(Ordering::Relaxed),
+                contended: entry.contended.load(Ordering::Relaxed),
+                wait_time: Duration::from_nanos(entry.wait_nanos.load(Ordering::Relaxed)),
+                max_wait_time: Duration::from_nanos(entry.max_wait_nanos.load(Ordering::Relaxed)),
+                callers,
+            }
+        })
+        .collect();
+    locks.sort_by(|a, b| b.wait_time.cmp(&a.wait_time).then(b.contended.cmp(&a.contended)));
+    Report { locks }
+}
+
+impl Report {
+    /// Only keep the `n` lock classes waited on the longest.
+    pub fn top(mut self, n: usize) -> Report {
+        self.locks.truncate(n);
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        json.push_str("{\"locks\":[");
+        for (ii, lock) in self.locks.iter().enumerate() {
+            if ii > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"name\":");
+            match lock.name {
+                Some(name) => push_json_string(&mut json, name),
+                None => json.push_str("null"),
+            }
+            json.push_str(",\"created\":");
+            push_json_string(&mut json, &lock.created.to_string());
+            let _ = write!(json,
+                           ",\"locks\":{},\"contended\":{},\"wait_ns\":{},\"max_wait_ns\":{},\
+                            \"callers\":[",
+                           lock.locks,
+                           lock.contended,
+                           nanos(lock.wait_time),
+                           nanos(lock.max_wait_time));
+            for (jj, caller) in lock.callers.iter().enumerate() {
+                if jj > 0 {
+                    json.push(',');
+                }
+                json.push_str("{\"site\":");
+                push_json_string(&mut json, &caller.site.to_string());
+                let _ = write!(json,
+                               ",\"contended\":{},\"wait_ns\":{}}}",
+                               caller.contended,
+                               nanos(caller.wait_time));
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "{:>10} {:>10} {:>14} {:>14}  lock",
+                 "locked",
+                 "contended",
+                 "total wait",
+                 "max wait")?;
+        for lock in &self.locks {
+            let name = match lock.name {
+                Some(name) => name.to_owned(),
+                None => format!("<unnamed, created at {}>", lock.created),
+            };
+            writeln!(f,
+                     "{:>10} {:>10} {:>14} {:>14}  {}",
+                     lock.locks,
+                     lock.contended,
+                     format!("{:?}", lock.wait_time),
+                     format!("{:?}", lock.max_wait_time),
+                     name)?;
+            for caller in &lock.callers {
+                writeln!(f,
+                         "{:>10} {:>10} {:>14} {:>14}    from {}",
+                         "",
+                         caller.contended,
+                         format!("{:?}", caller.wait_time),
+                         "",
+                         caller.site)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn nanos(time: Duration) -> u64 {
+    cmp::min(time.as_nanos(), u64::MAX as u128) as u64
+}
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
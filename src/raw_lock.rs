@@ -0,0 +1,101 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::sync::atomic::AtomicU32;
+
+use parker::Instant;
+#[cfg(feature = "stats")]
+use stats::Stats;
+
+/// A raw lock that a `Mutex` can be built on.
+///
+/// # Safety
+///
+/// Implementations must actually provide mutual exclusion, the
+/// `Mutex` relies on it for memory safety.  Once `lock` returns or
+/// `try_lock` or `try_lock_until` return true no other thread may
+/// take the lock until its holder unlocks it.
+pub unsafe trait RawLock {
+    /// An unlocked lock.
+    const INIT: Self;
+
+    /// Whether the lock may be shared between processes.  Such locks
+    /// are left out of anything that keeps process local state about
+    /// them, like the `lockdep` feature's lock order checks.
+    const PROCESS_SHARED: bool = false;
+
+    /// `lock_api::GuardSend` if a guard may be sent to and unlocked
+    /// on another thread, `lock_api::GuardNoSend` if the lock has to
+    /// be unlocked by the thread that locked it.
+    type GuardMarker;
+
+    fn lock(&self);
+
+    fn try_lock(&self) -> bool;
+
+    /// Like lock but gives up once the deadline passes.  Without
+    /// `std` there are no deadlines and this is never called.
+    fn try_lock_until(&self, deadline: Instant) -> bool;
+
+    /// Release the lock.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the holder of the lock.
+    unsafe fn unlock(&self);
+
+    /// Unlock and keep newly arriving threads from barging in ahead
+    /// of threads already waiting, if there are any.  Backends differ
+    /// in which waiter gets the lock, only the stack lock hands it to
+    /// a particular one.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the holder of the lock.
+    unsafe fn unlock_fair(&self) {
+        self.unlock();
+    }
+
+    /// Give waiting threads a turn with the lock and then take it
+    /// back.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called by the holder of the lock.
+    unsafe fn bump(&self) {
+        self.unlock_fair();
+        self.lock();
+    }
+
+    /// The futex word that condition variable waiters can be
+    /// requeued onto, if the lock has one.  Without one `notify_all`
+    /// has to wake up every waiter.
+    fn futex_word(&self) -> Option<&AtomicU32> {
+        None
+    }
+
+    /// Lock ahead of waiters with a lower priority.  Backends that
+    /// don't order their waiters just lock.
+    fn lock_with_priority(&self, _priority: u32) {
+        self.lock();
+    }
+
+    /// Lock after being requeued onto the futex word.
+    fn lock_requeued(&self) {
+        self.lock();
+    }
+
+    /// Add whatever events the lock counts to `stats`.
+    #[cfg(feature = "stats")]
+    fn add_stats(&self, _stats: &mut Stats) {}
+}
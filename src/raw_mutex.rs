@@ -11,84 +11,482 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
+#[cfg(not(target_arch = "x86_64"))]
 use libc;
+use lock_api;
 use dontshare::DontShare;
-use weakrand;
-use sleepfast;
 
+use backoff::{Backoff, Exponential};
+use parker::{DefaultParker, Parker};
+use raw_lock::RawLock;
 use stack_mutex;
+use stats;
+#[cfg(feature = "stats")]
+use stats::Stats;
 use tts_mutex;
 
-use std::thread;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 const NUM_FALLBACK: usize = 2;
-const MAX_EXP: usize = 8;
-const LOOPS: usize = 10;
 
 // A simple test-and test and set lock causes lots of intercore
 // commmunication when contended by lots of threads.  A StackMutex has
 // a bunch of overhead.  Use a test-and-test and set lock that falls
 // back to separate StackMutexs under heavy contention.
-pub struct RawMutex {
-    spin_mutex: DontShare<tts_mutex::RawMutex>,
-    fallback: [DontShare<stack_mutex::RawMutex>; NUM_FALLBACK],
+//
+// This is also exported so it can be used with the lock_api crate's
+// Mutex and guard types.
+//
+// The backoff policy and parker are shared by the spinning here and
+// the locks underneath.  Unless given one policy for both the
+// spinning here uses `B::SPIN_INIT` and so gives up sooner.
+//
+// Spinning only pays off while the holder is running.  An adaptive
+// lock has the holder publish which thread it is, the CPU it took the
+// lock on and when, and keep a moving average of how long the lock is
+// held.  Waiters stop spinning and queue up as soon as they find
+// themselves running on the holder's CPU or the holder has had the
+// lock for well over the usual time, both signs that it has been
+// descheduled.  Publishing costs every lock and unlock a clock read
+// so it is opt in.
+pub struct RawMutex<B: Backoff = Exponential, P: Parker = DefaultParker> {
+    spin_mutex: DontShare<tts_mutex::RawMutex<B, P>>,
+    fallback: [DontShare<stack_mutex::RawMutex<B, P>>; NUM_FALLBACK],
+    holder: DontShare<Holder>,
+    adaptive: bool,
+    backoff: B,
+    stats: stats::Counters,
+}
+
+struct Holder {
+    // The thread id in the high half and the CPU in the low half or
+    // zero when unlocked
+    owner: AtomicU64,
+    since: AtomicU64,
+    average: AtomicU64,
 }
-unsafe impl Send for RawMutex {}
-unsafe impl Sync for RawMutex {}
+unsafe impl<B: Backoff, P: Parker> Send for RawMutex<B, P> {}
+unsafe impl<B: Backoff, P: Parker> Sync for RawMutex<B, P> {}
 
-impl Default for RawMutex {
+impl<B: Backoff, P: Parker> Default for RawMutex<B, P> {
     fn default() -> Self {
-        Self::new()
+        Self::build(B::INIT, B::SPIN_INIT)
     }
 }
 
 impl RawMutex {
     #[inline]
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
+        RawMutex::build(Exponential::INIT, Exponential::SPIN_INIT)
+    }
+
+    /// A lock whose waiters stop spinning once the holder looks
+    /// descheduled instead of spinning for as long as the backoff
+    /// policy allows.  Worth it when threads outnumber CPUs, at the
+    /// price of reading the clock on every lock and unlock.
+    pub const fn adaptive() -> Self {
+        RawMutex { adaptive: true, ..RawMutex::new() }
+    }
+}
+
+impl<B: Backoff> RawMutex<B> {
+    #[inline]
+    pub const fn with_backoff(backoff: B) -> Self {
+        RawMutex::with_parker(backoff)
+    }
+}
+
+impl<B: Backoff, P: Parker> RawMutex<B, P> {
+    /// Like `with_backoff` but for a lock whose waiters sleep with a
+    /// parker other than the default, named in the type.
+    #[inline]
+    pub const fn with_parker(backoff: B) -> Self {
+        RawMutex::build(backoff, backoff)
+    }
+
+    const fn build(backoff: B, spin: B) -> Self {
         RawMutex {
-            spin_mutex: DontShare::new(tts_mutex::RawMutex::new()),
-            fallback: [DontShare::new(stack_mutex::RawMutex::new()),
-                       DontShare::new(stack_mutex::RawMutex::new())],
+            spin_mutex: DontShare::new(tts_mutex::RawMutex::with_parker(backoff)),
+            fallback: [DontShare::new(stack_mutex::RawMutex::with_parker(backoff)),
+                       DontShare::new(stack_mutex::RawMutex::with_parker(backoff))],
+            holder: DontShare::new(Holder {
+                owner: AtomicU64::new(0),
+                since: AtomicU64::new(0),
+                average: AtomicU64::new(0),
+            }),
+            adaptive: false,
+            backoff: spin,
+            stats: stats::Counters::new(),
         }
     }
 
+    pub fn try_lock(&self) -> bool {
+        if !self.spin_mutex.try_lock() {
+            return false;
+        }
+        self.stats.fast_path();
+        self.acquired();
+        true
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.spin_mutex.is_locked()
+    }
+
     pub fn lock(&self) {
-        // Spin a bit before falling back to the stack lock
+        if self.spin() {
+            return;
+        }
+
+        let lock = self.fallback_index();
+        {
+            self.fallback[lock].lock();
+
+            self.lock_front();
+
+            self.fallback[lock].unlock();
+        }
+    }
+
+    /// Lock ahead of lower priority waiters queued on the same
+    /// fallback stack.  Waiters that are still spinning can't be
+    /// ordered.
+    pub fn lock_with_priority(&self, priority: u32) {
+        if self.spin() {
+            return;
+        }
+
+        let lock = self.fallback_index();
+        {
+            self.fallback[lock].lock_with_priority(priority);
+
+            self.lock_front();
+
+            self.fallback[lock].unlock();
+        }
+    }
+
+    /// Like lock but gives up once the deadline passes.
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        if self.spin() {
+            return true;
+        }
+
+        let lock = self.fallback_index();
+        {
+            if !self.fallback[lock].try_lock_until(deadline) {
+                return false;
+            }
+
+            let locked = self.spin() || self.spin_mutex.lock_contended_until(Some(deadline));
+            if locked {
+                self.acquired();
+            }
+
+            self.fallback[lock].unlock();
+
+            locked
+        }
+    }
+
+    pub fn unlock(&self) {
+        self.released();
+        self.spin_mutex.unlock();
+    }
+
+    /// Keep spinning threads off the spin lock if a thread is asleep
+    /// waiting for it.  The lock goes to one of the waiters on the
+    /// contended path: the front thread of either fallback stack or
+    /// a requeued condition variable waiter, whichever takes it
+    /// first.  So the handoff is not to a particular thread.  The
+    /// front thread in turn passes its stack lock straight on to the
+    /// next node on its stack.
+    pub fn unlock_fair(&self) {
+        self.released();
+        self.spin_mutex.unlock_fair();
+    }
+
+    /// The futex word that condition variable waiters can be
+    /// requeued onto.
+    pub fn futex_word(&self) -> &AtomicU32 {
+        self.spin_mutex.futex_word()
+    }
+
+    /// Lock after being requeued onto the futex word.  Requeued
+    /// threads skip the stack locks and go straight for the spin
+    /// lock.
+    pub fn lock_requeued(&self) {
+        self.spin_mutex.lock_contended();
+        self.acquired();
+    }
+
+    // The thread at the front of a fallback stack spins again and
+    // then sleeps on the spin lock.
+    fn lock_front(&self) {
+        if !self.spin() {
+            self.spin_mutex.lock_contended();
+            self.acquired();
+        }
+    }
+
+    // Spin a bit while the holder is running before falling back to
+    // the stack lock
+    fn spin(&self) -> bool {
         let mut counter = 0;
         loop {
-            if self.spin_mutex.try_lock() {
-                return;
+            if self.try_lock() {
+                self.stats.spins(counter);
+                return true;
+            }
+            if counter > self.backoff.spins() {
+                self.stats.spins(counter);
+                return false;
             }
-            if counter > LOOPS {
-                break;
+            if !self.holder_running() {
+                self.stats.spins(counter);
+                self.stats.descheduled();
+                return false;
             }
-            thread::yield_now();
 
-            let exp = if counter < MAX_EXP {
-                1 << counter
-            } else {
-                1 << MAX_EXP
-            };
+            self.backoff.backoff::<P>(counter);
 
             counter = counter.wrapping_add(1);
+        }
+    }
 
-            let spins = weakrand::rand(1, exp);
+    fn acquired(&self) {
+        if !self.adaptive {
+            return;
+        }
+        let (now, cpu) = now_and_cpu();
+        let owner = (thread_id() as u64) << 32 | cpu as u64;
+        self.holder.since.store(now, Ordering::Relaxed);
+        self.holder.owner.store(owner, Ordering::Relaxed);
+    }
 
-            sleepfast::pause_times(spins as usize);
+    // Only the holder writes the average so it needs no atomic
+    // read-modify-write
+    fn released(&self) {
+        if !self.adaptive {
+            return;
         }
+        let (now, _) = now_and_cpu();
+        let held = now.saturating_sub(self.holder.since.load(Ordering::Relaxed));
+        let average = self.holder.average.load(Ordering::Relaxed);
+        self.holder.average.store(average - average / 8 + held / 8, Ordering::Relaxed);
+        self.holder.owner.store(0, Ordering::Relaxed);
+    }
 
-        let cpu = unsafe { libc::sched_getcpu() } as usize;
-        let lock = cpu as usize % NUM_FALLBACK;
-        {
-            self.fallback[lock].lock();
+    // Guess whether the holder is still on a CPU.  A wrong guess only
+    // costs some spinning or an early sleep.
+    fn holder_running(&self) -> bool {
+        if !self.adaptive {
+            return true;
+        }
+        let owner = self.holder.owner.load(Ordering::Relaxed);
+        if owner == 0 {
+            // Just released or not published yet
+            return true;
+        }
+        let (now, cpu) = now_and_cpu();
+        if owner as u32 == cpu {
+            // We are running on its CPU so it isn't
+            return false;
+        }
+        let since = self.holder.since.load(Ordering::Relaxed);
+        if self.holder.owner.load(Ordering::Relaxed) != owner {
+            // The lock changed hands so it is making progress
+            return true;
+        }
+        let average = self.holder.average.load(Ordering::Relaxed);
+        now.saturating_sub(since) < average.saturating_mul(2).saturating_add(min_spin())
+    }
 
-            self.spin_mutex.lock();
+    fn fallback_index(&self) -> usize {
+        let index = P::current_cpu() % NUM_FALLBACK;
+        self.stats.fallback(index);
+        index
+    }
 
-            self.fallback[lock].unlock();
+    /// Add up the counts of the spin lock, both stack locks and the
+    /// spinning and falling back done here.
+    #[cfg(feature = "stats")]
+    pub fn add_stats(&self, stats: &mut Stats) {
+        self.stats.add_to(stats);
+        self.spin_mutex.add_stats(stats);
+        for fallback in self.fallback.iter() {
+            RawLock::add_stats(&**fallback, stats);
         }
     }
+}
 
-    pub fn unlock(&self) {
-        self.spin_mutex.unlock();
+unsafe impl<B: Backoff, P: Parker> lock_api::RawMutex for RawMutex<B, P> {
+    const INIT: Self = RawMutex::build(B::INIT, B::SPIN_INIT);
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
+    }
+
+    fn is_locked(&self) -> bool {
+        RawMutex::is_locked(self)
+    }
+}
+
+unsafe impl<B: Backoff, P: Parker> lock_api::RawMutexFair for RawMutex<B, P> {
+    unsafe fn unlock_fair(&self) {
+        RawMutex::unlock_fair(self);
+    }
+}
+
+unsafe impl<B: Backoff, P: Parker> lock_api::RawMutexTimed for RawMutex<B, P> {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => RawMutex::try_lock_until(self, deadline),
+            None => {
+                RawMutex::lock(self);
+                true
+            }
+        }
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+}
+
+unsafe impl<B: Backoff, P: Parker> RawLock for RawMutex<B, P> {
+    const INIT: Self = RawMutex::build(B::INIT, B::SPIN_INIT);
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
+    }
+
+    unsafe fn unlock_fair(&self) {
+        RawMutex::unlock_fair(self);
+    }
+
+    fn futex_word(&self) -> Option<&AtomicU32> {
+        if P::FUTEX {
+            Some(RawMutex::futex_word(self))
+        } else {
+            None
+        }
+    }
+
+    fn lock_with_priority(&self, priority: u32) {
+        RawMutex::lock_with_priority(self, priority);
+    }
+
+    fn lock_requeued(&self) {
+        RawMutex::lock_requeued(self);
+    }
+
+    #[cfg(feature = "stats")]
+    fn add_stats(&self, stats: &mut Stats) {
+        RawMutex::add_stats(self, stats);
+    }
+}
+
+thread_local!(static THREAD_ID: u32 = unsafe { syscall!(GETTID) as u32 });
+
+fn thread_id() -> u32 {
+    THREAD_ID.with(|id| *id)
+}
+
+// Keep spinning for at least this long however short the lock is
+// usually held, about 50 microseconds.
+#[cfg(target_arch = "x86_64")]
+const MIN_SPIN_TICKS: u64 = 100000;
+const MIN_SPIN_NANOS: u64 = 50000;
+
+// The time stamp counter and the CPU Linux stores in its auxiliary
+// register, read together by a single instruction on CPUs that have
+// it.
+#[cfg(target_arch = "x86_64")]
+fn now_and_cpu() -> (u64, u32) {
+    if !has_rdtscp() {
+        return clock_and_cpu();
+    }
+    let mut aux = 0;
+    let now = unsafe { x86_64::__rdtscp(&mut aux) };
+    (now, aux & 0xfff)
+}
+
+// Not among the features std detects, CPUID reports it in bit 27 of
+// EDX for extended leaf 1.  Zero until checked, then one plus the
+// answer.
+#[cfg(target_arch = "x86_64")]
+fn has_rdtscp() -> bool {
+    static HAS_RDTSCP: AtomicU32 = AtomicU32::new(0);
+
+    let cached = HAS_RDTSCP.load(Ordering::Relaxed);
+    if cached != 0 {
+        return cached == 2;
+    }
+    let has = x86_64::__get_cpuid_max(0x80000000).0 >= 0x80000001 &&
+              x86_64::__cpuid(0x80000001).edx & (1 << 27) != 0;
+    HAS_RDTSCP.store(1 + has as u32, Ordering::Relaxed);
+    has
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn now_and_cpu() -> (u64, u32) {
+    clock_and_cpu()
+}
+
+// `MIN_SPIN_TICKS` or `MIN_SPIN_NANOS` in the units `now_and_cpu`
+// counts time in.
+#[cfg(target_arch = "x86_64")]
+fn min_spin() -> u64 {
+    if has_rdtscp() {
+        MIN_SPIN_TICKS
+    } else {
+        MIN_SPIN_NANOS
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn min_spin() -> u64 {
+    MIN_SPIN_NANOS
+}
+
+// The monotonic clock in nanoseconds and the current CPU.
+fn clock_and_cpu() -> (u64, u32) {
+    let mut time: libc::timespec = unsafe { ::std::mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
     }
+    let now = (time.tv_sec as u64).wrapping_mul(1000000000).wrapping_add(time.tv_nsec as u64);
+    let cpu = unsafe { libc::sched_getcpu() } as u32;
+    (now, cpu)
 }
@@ -0,0 +1,113 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use dontshare::DontShare;
+
+use futex;
+use stack_mutex;
+
+const WRITER: u32 = 1 << 31;
+
+/// A reader-writer lock that queues blocked threads on a stack lock.
+///
+/// Readers that find no writer just bump a count.  Writers, and
+/// readers that find a writer, queue up on the stack lock so that
+/// each waiter spins or sleeps on its own node.  A writer that gets
+/// through the stack lock shuts out new readers and then only waits
+/// for the readers already inside to drain.  Readers can be starved
+/// by a steady stream of writers but writers are never starved by
+/// readers.
+pub struct RawRwLock {
+    state: DontShare<AtomicU32>,
+    queue: DontShare<stack_mutex::RawMutex>,
+}
+unsafe impl Send for RawRwLock {}
+unsafe impl Sync for RawRwLock {}
+
+impl Default for RawRwLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RawRwLock {
+    #[inline]
+    pub fn new() -> Self {
+        RawRwLock {
+            state: DontShare::new(AtomicU32::new(0)),
+            queue: DontShare::new(stack_mutex::RawMutex::new()),
+        }
+    }
+
+    pub fn try_read(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & WRITER == 0 {
+            match self.state
+                .compare_exchange_weak(state, state + 1, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(newstate) => state = newstate,
+            }
+        }
+        false
+    }
+
+    pub fn read(&self) {
+        if self.try_read() {
+            return;
+        }
+
+        // Queue up behind the writer.  Writers clear their bit before
+        // leaving the queue so there is none by the time we get in.
+        self.queue.lock();
+        self.state.fetch_add(1, Ordering::SeqCst);
+        self.queue.unlock();
+    }
+
+    pub fn read_unlock(&self) {
+        if self.state.fetch_sub(1, Ordering::SeqCst) == WRITER | 1 {
+            futex::wake(&self.state, 1);
+        }
+    }
+
+    pub fn try_write(&self) -> bool {
+        if !self.queue.try_lock() {
+            return false;
+        }
+        if self.state
+            .compare_exchange(0, WRITER, Ordering::SeqCst, Ordering::Relaxed)
+            .is_err() {
+            self.queue.unlock();
+            return false;
+        }
+        true
+    }
+
+    pub fn write(&self) {
+        self.queue.lock();
+
+        let mut state = self.state.fetch_or(WRITER, Ordering::SeqCst) | WRITER;
+        while state != WRITER {
+            futex::wait(&self.state, state, None);
+            state = self.state.load(Ordering::SeqCst);
+        }
+    }
+
+    pub fn write_unlock(&self) {
+        // Nobody else can touch the state while we have both the
+        // writer bit and the queue.
+        self.state.store(0, Ordering::SeqCst);
+        self.queue.unlock();
+    }
+}
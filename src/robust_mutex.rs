@@ -0,0 +1,371 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::cell::{Cell, UnsafeCell};
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use futex;
+
+const FUTEX_WAITERS: u32 = 0x80000000;
+const FUTEX_OWNER_DIED: u32 = 0x40000000;
+const FUTEX_TID_MASK: u32 = 0x3fffffff;
+
+// What pthreads uses too.  No thread can have this id.
+const NOT_RECOVERABLE: u32 = FUTEX_TID_MASK;
+
+/// A mutex that the kernel releases if its owner dies.  The word
+/// holds the owner's thread id and every held lock is linked onto
+/// the thread's robust futex list.  When a thread exits, or its
+/// whole process dies, the kernel marks the locks it still holds as
+/// having a dead owner and wakes up a waiter.
+///
+/// The next thread to lock it gets a `RobustLockError::OwnerDied`
+/// carrying the guard.  It should repair the data and call
+/// `RobustMutexGuard::mark_consistent`.  If the guard is dropped
+/// without doing so the lock becomes permanently unusable and every
+/// later `lock` returns `RobustLockError::NotRecoverable`.
+///
+/// The lock holds no pointers that other processes use so like
+/// `SharedMutex` it can be placed in memory shared between
+/// processes.  Waiters sleep on non-private futexes.
+///
+/// The kernel only tracks one robust list per thread and glibc
+/// normally owns it.  While a thread holds any of these locks the
+/// thread's list is swapped for our own and glibc's is put back once
+/// it holds none.  So a robust pthread mutex held at the same time is
+/// not recovered if the thread dies.
+pub struct RobustMutex<T: ?Sized> {
+    raw: RawRobustMutex,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for RobustMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for RobustMutex<T> {}
+
+pub struct RobustMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RobustMutex<T>,
+    _phantom: PhantomData<&'a mut T>,
+    // The kernel releases the lock when the owning thread dies so the
+    // guard must stay on the thread that locked it
+    _not_send: PhantomData<*const ()>,
+}
+unsafe impl<'a, T: ?Sized + Sync> Sync for RobustMutexGuard<'a, T> {}
+
+/// Why a robust lock was not cleanly acquired.
+pub enum RobustLockError<G> {
+    /// The previous owner died holding the lock.  The lock is now
+    /// held but the data may be inconsistent.
+    OwnerDied(G),
+    /// A previous owner died and its successor never marked the data
+    /// consistent.
+    NotRecoverable,
+}
+
+pub type RobustLockResult<G> = Result<G, RobustLockError<G>>;
+
+impl<G> fmt::Debug for RobustLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RobustLockError::OwnerDied(..) => "OwnerDied(..)".fmt(f),
+            RobustLockError::NotRecoverable => "NotRecoverable".fmt(f),
+        }
+    }
+}
+
+impl<G> fmt::Display for RobustLockError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RobustLockError::OwnerDied(..) => "the owner of the lock died holding it".fmt(f),
+            RobustLockError::NotRecoverable => "the lock is not recoverable".fmt(f),
+        }
+    }
+}
+
+impl<G> Error for RobustLockError<G> {}
+
+impl<T> RobustMutex<T> {
+    pub const fn new(val: T) -> Self {
+        RobustMutex {
+            raw: RawRobustMutex::new(),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RobustMutex<T> {
+    pub fn lock(&self) -> RobustLockResult<RobustMutexGuard<'_, T>> {
+        match self.raw.lock() {
+            Acquired::Clean => Ok(RobustMutexGuard::new(self)),
+            Acquired::OwnerDied => Err(RobustLockError::OwnerDied(RobustMutexGuard::new(self))),
+            Acquired::NotRecoverable => Err(RobustLockError::NotRecoverable),
+        }
+    }
+}
+
+impl<T: Default> Default for RobustMutex<T> {
+    fn default() -> RobustMutex<T> {
+        RobustMutex::new(Default::default())
+    }
+}
+
+impl<'a, T: ?Sized + 'a> RobustMutexGuard<'a, T> {
+    fn new(lock: &'a RobustMutex<T>) -> RobustMutexGuard<'a, T> {
+        RobustMutexGuard {
+            lock,
+            _phantom: PhantomData,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Declare the data repaired after the previous owner died so
+    /// the lock can go on being used.
+    pub fn mark_consistent(this: &mut Self) {
+        unsafe {
+            *this.lock.raw.owner_died.get() = false;
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for RobustMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for RobustMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for RobustMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+    }
+}
+
+enum Acquired {
+    Clean,
+    OwnerDied,
+    NotRecoverable,
+}
+
+// The layout the kernel expects of an entry on the robust list.  The
+// futex word always follows the link at the same offset.
+#[repr(C)]
+struct RobustList {
+    next: UnsafeCell<*const RobustList>,
+}
+
+#[repr(C)]
+struct RawRobustMutex {
+    list: RobustList,
+    word: AtomicU32,
+    // Only touched by the lock holder
+    owner_died: UnsafeCell<bool>,
+}
+
+const FUTEX_OFFSET: isize = mem::size_of::<RobustList>() as isize;
+
+impl RawRobustMutex {
+    const fn new() -> Self {
+        RawRobustMutex {
+            list: RobustList { next: UnsafeCell::new(ptr::null()) },
+            word: AtomicU32::new(0),
+            owner_died: UnsafeCell::new(false),
+        }
+    }
+
+    fn lock(&self) -> Acquired {
+        let tid = unsafe { syscall!(GETTID) } as u32;
+        let entry = &self.list as *const RobustList;
+
+        ROBUST_LIST.with(|list| {
+            list.enter();
+
+            // Should we die halfway through the kernel still checks
+            // this entry.
+            list.head.list_op_pending.set(entry);
+            let acquired = self.acquire(tid);
+            match acquired {
+                Acquired::NotRecoverable => {
+                    list.head.list_op_pending.set(ptr::null());
+                    list.leave();
+                }
+                _ => {
+                    list.insert(entry);
+                    list.head.list_op_pending.set(ptr::null());
+                }
+            }
+            acquired
+        })
+    }
+
+    fn acquire(&self, tid: u32) -> Acquired {
+        // Once we have slept there might be others asleep as well
+        let mut waiters = 0;
+        loop {
+            let val = self.word.load(Ordering::Relaxed);
+            if val == NOT_RECOVERABLE {
+                return Acquired::NotRecoverable;
+            }
+
+            if val & FUTEX_TID_MASK == 0 {
+                if self.word
+                    .compare_exchange_weak(val, tid | waiters, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_err() {
+                    continue;
+                }
+                if val & FUTEX_OWNER_DIED != 0 {
+                    unsafe {
+                        *self.owner_died.get() = true;
+                    }
+                    return Acquired::OwnerDied;
+                }
+                return Acquired::Clean;
+            }
+
+            if val & FUTEX_WAITERS == 0 &&
+               self.word
+                .compare_exchange_weak(val,
+                                       val | FUTEX_WAITERS,
+                                       Ordering::SeqCst,
+                                       Ordering::Relaxed)
+                .is_err() {
+                continue;
+            }
+
+            futex::wait_shared(&self.word, val | FUTEX_WAITERS, None);
+            waiters = FUTEX_WAITERS;
+        }
+    }
+
+    fn unlock(&self) {
+        let entry = &self.list as *const RobustList;
+
+        ROBUST_LIST.with(|list| {
+            list.head.list_op_pending.set(entry);
+            list.remove(entry);
+
+            let owner_died = unsafe { mem::replace(&mut *self.owner_died.get(), false) };
+            if owner_died {
+                // Never made consistent, lock everybody out for good
+                self.word.store(NOT_RECOVERABLE, Ordering::SeqCst);
+                futex::wake_shared(&self.word, i32::MAX as u32);
+            } else if self.word.swap(0, Ordering::SeqCst) & FUTEX_WAITERS != 0 {
+                futex::wake_shared(&self.word, 1);
+            }
+
+            list.head.list_op_pending.set(ptr::null());
+            list.leave();
+        })
+    }
+}
+
+#[repr(C)]
+struct RobustListHead {
+    list: RobustList,
+    futex_offset: isize,
+    list_op_pending: Cell<*const RobustList>,
+}
+
+// The list of robust locks a thread holds plus whatever list was
+// registered before we took over.
+struct ThreadList {
+    head: RobustListHead,
+    held: Cell<usize>,
+    saved_head: Cell<usize>,
+    saved_len: Cell<usize>,
+}
+
+thread_local!(static ROBUST_LIST: ThreadList = const {
+    ThreadList {
+        head: RobustListHead {
+            list: RobustList { next: UnsafeCell::new(ptr::null()) },
+            futex_offset: FUTEX_OFFSET,
+            list_op_pending: Cell::new(ptr::null()),
+        },
+        held: Cell::new(0),
+        saved_head: Cell::new(0),
+        saved_len: Cell::new(0),
+    }
+});
+
+impl ThreadList {
+    fn enter(&self) {
+        let held = self.held.get();
+        self.held.set(held + 1);
+        if held > 0 {
+            return;
+        }
+
+        unsafe {
+            // An empty list points back at itself
+            *self.head.list.next.get() = &self.head.list;
+
+            let mut saved_head: usize = 0;
+            let mut saved_len: usize = 0;
+            syscall!(GET_ROBUST_LIST,
+                     0,
+                     &mut saved_head as *mut usize,
+                     &mut saved_len as *mut usize);
+            self.saved_head.set(saved_head);
+            self.saved_len.set(saved_len);
+
+            syscall!(SET_ROBUST_LIST,
+                     &self.head as *const RobustListHead,
+                     mem::size_of::<RobustListHead>());
+        }
+    }
+
+    fn leave(&self) {
+        let held = self.held.get() - 1;
+        self.held.set(held);
+        if held > 0 {
+            return;
+        }
+
+        unsafe {
+            syscall!(SET_ROBUST_LIST, self.saved_head.get(), self.saved_len.get());
+        }
+    }
+
+    fn insert(&self, entry: *const RobustList) {
+        unsafe {
+            *(*entry).next.get() = *self.head.list.next.get();
+            *self.head.list.next.get() = entry;
+        }
+    }
+
+    // Locks are usually released in the reverse order they were
+    // taken so this rarely has to walk far.
+    fn remove(&self, entry: *const RobustList) {
+        unsafe {
+            let mut prev = &self.head.list as *const RobustList;
+            while *(*prev).next.get() != entry {
+                prev = *(*prev).next.get();
+            }
+            *(*prev).next.get() = *(*entry).next.get();
+        }
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use raw_rwlock::RawRwLock;
+
+/// A reader-writer lock.  Writers are preferred over readers, see
+/// `RawRwLock` for the details.  Like the standard library's
+/// reader-writer lock it is poisoned if a writer panics.
+pub struct RwLock<T: ?Sized> {
+    lock: RawRwLock,
+    poison: AtomicBool,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'r, T: ?Sized + 'r> {
+    lock: &'r RwLock<T>,
+    _phantom: PhantomData<&'r T>,
+}
+
+pub struct RwLockWriteGuard<'r, T: ?Sized + 'r> {
+    lock: &'r RwLock<T>,
+    panicking: bool,
+    _phantom: PhantomData<&'r mut T>,
+}
+
+impl<T> RwLock<T> {
+    pub fn new(val: T) -> Self {
+        RwLock {
+            lock: RawRwLock::new(),
+            poison: AtomicBool::new(false),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let data = self.data.into_inner();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.lock.read();
+        RwLockReadGuard::new(self)
+    }
+
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        if !self.lock.try_read() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(RwLockReadGuard::new(self)?)
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.lock.write();
+        RwLockWriteGuard::new(self)
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        if !self.lock.try_write() {
+            return Err(TryLockError::WouldBlock);
+        }
+        Ok(RwLockWriteGuard::new(self)?)
+    }
+
+    /// Whether a thread panicked while holding the write lock.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.load(Ordering::Relaxed)
+    }
+
+    /// Mark the data as consistent again after a panic.
+    pub fn clear_poison(&self) {
+        self.poison.store(false, Ordering::Relaxed);
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(Default::default())
+    }
+}
+
+impl<'r, T: ?Sized + 'r> RwLockReadGuard<'r, T> {
+    fn new(lock: &'r RwLock<T>) -> LockResult<RwLockReadGuard<'r, T>> {
+        let guard = RwLockReadGuard {
+            lock,
+            _phantom: PhantomData,
+        };
+        if lock.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<'r, T: ?Sized + 'r> RwLockWriteGuard<'r, T> {
+    fn new(lock: &'r RwLock<T>) -> LockResult<RwLockWriteGuard<'r, T>> {
+        let guard = RwLockWriteGuard {
+            lock,
+            panicking: thread::panicking(),
+            _phantom: PhantomData,
+        };
+        if lock.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'r, T: ?Sized + 'r> Drop for RwLockReadGuard<'r, T> {
+    fn drop(&mut self) {
+        self.lock.lock.read_unlock();
+    }
+}
+
+impl<'r, T: ?Sized + 'r> Drop for RwLockWriteGuard<'r, T> {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.lock.poison.store(true, Ordering::Relaxed);
+        }
+        self.lock.lock.write_unlock();
+    }
+}
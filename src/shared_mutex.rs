@@ -0,0 +1,286 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::sync::atomic;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Instant;
+
+use dontshare::DontShare;
+use lock_api;
+
+use backoff::{Backoff, Exponential};
+use futex;
+use parker::Futex;
+use raw_lock::RawLock;
+
+const NUM_SLOTS: usize = 64;
+
+const WAITING: u32 = 0;
+const SIGNALLED: u32 = 1;
+const ABANDONED: u32 = 2;
+
+// The head packs a lock bit, a tag against ABA and the index plus
+// one of the slot on top of the stack.  Zero means an empty stack.
+const LOCKED_BIT: u64 = 1;
+const TAG_OFFSET: u64 = 1;
+const TAG_SIZE: u64 = 31;
+const TOP_OFFSET: u64 = 32;
+
+/// A stack lock that can be placed in memory shared between
+/// processes such as a `memfd` or `shm_open` mapping.  It holds no
+/// pointers and so does not care at which address each process maps
+/// it.  Waiters can't push nodes on their own stacks either so
+/// instead they claim one of a fixed number of slots inside the lock
+/// and the stack links slots by index.  Waiters sleep on
+/// non-private futexes.
+///
+/// If all the slots are taken further waiters back off according to
+/// `B` and retry instead of queueing.  Every process sharing the lock
+/// must use the same policy type.  A process that dies holding the
+/// lock or while waiting for it leaves it stuck.
+pub struct RawMutex<B: Backoff = Exponential> {
+    head: DontShare<AtomicU64>,
+    used: DontShare<AtomicU64>,
+    slots: [DontShare<Slot>; NUM_SLOTS],
+    backoff: B,
+}
+unsafe impl<B: Backoff> Send for RawMutex<B> {}
+unsafe impl<B: Backoff> Sync for RawMutex<B> {}
+
+struct Slot {
+    next: AtomicU32,
+    state: AtomicU32,
+}
+
+impl<B: Backoff> Default for RawMutex<B> {
+    fn default() -> Self {
+        Self::with_backoff(B::INIT)
+    }
+}
+
+impl RawMutex {
+    #[inline]
+    pub const fn new() -> Self {
+        RawMutex::with_backoff(Exponential::INIT)
+    }
+}
+
+impl<B: Backoff> RawMutex<B> {
+    #[inline]
+    pub const fn with_backoff(backoff: B) -> Self {
+        RawMutex {
+            head: DontShare::new(AtomicU64::new(0)),
+            used: DontShare::new(AtomicU64::new(0)),
+            slots: [const {
+                DontShare::new(Slot {
+                    next: AtomicU32::new(0),
+                    state: AtomicU32::new(WAITING),
+                })
+            }; NUM_SLOTS],
+            backoff,
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        let mut head = self.head.load(Ordering::Relaxed);
+        while head & LOCKED_BIT == 0 {
+            let new = Self::pack(Self::top(head), Self::tag(head).wrapping_add(1), true);
+
+            match self.head
+                .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(newhead) => head = newhead,
+            }
+        }
+        false
+    }
+
+    pub fn lock(&self) {
+        self.lock_until(None);
+    }
+
+    /// Like lock but gives up once the deadline passes.  A waiter
+    /// that times out marks its slot abandoned and the unlocker
+    /// frees it.
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.lock_until(Some(deadline))
+    }
+
+    fn lock_until(&self, deadline: Option<Instant>) -> bool {
+        let mut counter = 0;
+        loop {
+            if self.try_lock() {
+                return true;
+            }
+
+            if let Some(index) = self.claim() {
+                return self.wait_on(index, deadline);
+            }
+
+            // Every slot is taken so spin instead
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    return false;
+                }
+            }
+
+            self.backoff.backoff::<Futex>(counter);
+
+            counter = counter.wrapping_add(1);
+        }
+    }
+
+    fn wait_on(&self, index: u32, deadline: Option<Instant>) -> bool {
+        let slot = &self.slots[index as usize];
+        slot.state.store(WAITING, Ordering::Relaxed);
+
+        if !self.push(index) {
+            self.release(index);
+            return true;
+        }
+
+        while slot.state.load(Ordering::SeqCst) != SIGNALLED {
+            if !futex::wait_shared(&slot.state, WAITING, deadline) &&
+               slot.state
+                .compare_exchange(WAITING, ABANDONED, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok() {
+                return false;
+            }
+        }
+        self.release(index);
+        true
+    }
+
+    /// Either acquire the lock and return false or push the slot and
+    /// return true.
+    fn push(&self, index: u32) -> bool {
+        let slot = &self.slots[index as usize];
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            let locked = head & LOCKED_BIT != 0;
+            let new = if locked {
+                slot.next.store(Self::top(head), Ordering::Relaxed);
+                Self::pack(index + 1, Self::tag(head).wrapping_add(1), true)
+            } else {
+                Self::pack(Self::top(head), Self::tag(head).wrapping_add(1), true)
+            };
+
+            match self.head
+                .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => return locked,
+                Err(newhead) => head = newhead,
+            }
+        }
+    }
+
+    pub fn unlock(&self) {
+        loop {
+            let index = match self.pop() {
+                None => break,
+                Some(index) => index,
+            };
+            let slot = &self.slots[index as usize];
+            if slot.state
+                .compare_exchange(WAITING, SIGNALLED, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok() {
+                futex::wake_shared(&slot.state, 1);
+                break;
+            }
+            // The waiter timed out and left the slot to us.  We
+            // still hold the lock so move on to the next one.
+            self.release(index);
+        }
+    }
+
+    /// Pop the next waiter to pass the lock to.  If there is none
+    /// release the lock.  Must hold the lock.
+    fn pop(&self) -> Option<u32> {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            assert!(head & LOCKED_BIT != 0);
+            let top = Self::top(head);
+            let new = if top == 0 {
+                Self::pack(0, Self::tag(head).wrapping_add(1), false)
+            } else {
+                atomic::fence(Ordering::Acquire);
+                let next = self.slots[top as usize - 1].next.load(Ordering::Relaxed);
+                Self::pack(next, Self::tag(head).wrapping_add(1), true)
+            };
+
+            match self.head
+                .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => {
+                    return if top == 0 { None } else { Some(top - 1) };
+                }
+                Err(newhead) => head = newhead,
+            }
+        }
+    }
+
+    fn claim(&self) -> Option<u32> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        while used != !0 {
+            let index = (!used).trailing_zeros();
+            match self.used.compare_exchange_weak(used,
+                                                  used | 1 << index,
+                                                  Ordering::SeqCst,
+                                                  Ordering::Relaxed) {
+                Ok(_) => return Some(index),
+                Err(newused) => used = newused,
+            }
+        }
+        None
+    }
+
+    fn release(&self, index: u32) {
+        self.used.fetch_and(!(1 << index), Ordering::SeqCst);
+    }
+
+    fn pack(top: u32, tag: u32, locked: bool) -> u64 {
+        let lock_bit = if locked { LOCKED_BIT } else { 0 };
+        let tag_bits = (tag as u64) & ((1 << TAG_SIZE) - 1);
+        (top as u64) << TOP_OFFSET | tag_bits << TAG_OFFSET | lock_bit
+    }
+
+    fn top(head: u64) -> u32 {
+        (head >> TOP_OFFSET) as u32
+    }
+
+    fn tag(head: u64) -> u32 {
+        ((head >> TAG_OFFSET) & ((1 << TAG_SIZE) - 1)) as u32
+    }
+}
+
+unsafe impl<B: Backoff> RawLock for RawMutex<B> {
+    const INIT: Self = RawMutex::with_backoff(B::INIT);
+
+    const PROCESS_SHARED: bool = true;
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
+    }
+}
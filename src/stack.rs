@@ -0,0 +1,213 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic;
+use std::sync::atomic::Ordering;
+
+pub const DEFAULT_MAX_BYPASS: usize = 64;
+
+/// The head of a stack: a pointer to the top node packed together
+/// with a lock bit and a tag against ABA.
+pub trait Head {
+    type Node;
+    type Word: Copy;
+
+    fn load(&self) -> Self::Word;
+
+    fn top(word: Self::Word) -> *mut Self::Node;
+
+    fn locked(word: Self::Word) -> bool;
+
+    /// If the head is still `old` replace it with `top` and `locked`
+    /// and the next tag.  Otherwise return the current head.  May
+    /// fail spuriously.
+    fn replace(&self, old: Self::Word, top: *mut Self::Node, locked: bool) -> Result<(), Self::Word>;
+}
+
+/// A node that can be pushed onto a stack.
+pub trait Link {
+    /// The next node down the stack or along the holder's queue.
+    unsafe fn next(node: *mut Self) -> *mut *mut Self;
+
+    unsafe fn priority(node: *mut Self) -> u32;
+}
+
+/// The Treiber stack of waiters shared by the stack lock and the
+/// async mutex, plus the queue of waiters the lock holder took off
+/// it.  The lock bit in the head is the lock itself.  Whoever holds
+/// it pops nodes and hands them the lock, everybody else only
+/// pushes.  So nodes only ever leave the stack through the holder
+/// and the tags work.
+///
+/// Callers pass `retry` to back off after losing a race on the
+/// head.
+pub struct Stack<H: Head> {
+    head: H,
+    // Only touched by the lock holder
+    queue: UnsafeCell<*mut H::Node>,
+    bypassed: UnsafeCell<usize>,
+}
+
+impl<H: Head> Stack<H>
+    where H::Node: Link
+{
+    pub const fn new(head: H) -> Self {
+        Stack {
+            head,
+            queue: UnsafeCell::new(ptr::null_mut()),
+            bypassed: UnsafeCell::new(0),
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        let mut head = self.head.load();
+        while !H::locked(head) {
+            match self.head.replace(head, H::top(head), true) {
+                Ok(()) => return true,
+                Err(newhead) => head = newhead,
+            }
+        }
+        false
+    }
+
+    /// Either acquire the lock and return false or push the node and
+    /// return true.
+    pub fn push<F: FnMut()>(&self, node: *mut H::Node, mut retry: F) -> bool {
+        let mut head = self.head.load();
+        loop {
+            let locked = H::locked(head);
+            let top = if locked {
+                // On a locked stack push the node
+                unsafe {
+                    *H::Node::next(node) = H::top(head);
+                }
+                node
+            } else {
+                // Acquire an unlocked stack
+                H::top(head)
+            };
+
+            match self.head.replace(head, top, true) {
+                Ok(()) => return locked,
+                Err(newhead) => head = newhead,
+            }
+
+            retry();
+        }
+    }
+
+    /// Take the next waiter to pass the lock to.  If there is none
+    /// release the lock and return null.  Must hold the lock.
+    ///
+    /// After `max_bypass` waiters have been popped off the top the
+    /// whole stack is taken and served oldest first.  While waiters
+    /// with a priority are about the whole stack is merged into the
+    /// queue by priority every time instead, pass `prioritized` then.
+    pub unsafe fn next_waiter<F: FnMut()>(&self,
+                                         max_bypass: usize,
+                                         prioritized: bool,
+                                         mut retry: F)
+                                         -> *mut H::Node {
+        // Raw pointers as merge_stack writes to the queue as well
+        let queue = self.queue.get();
+        let bypassed = self.bypassed.get();
+
+        if prioritized {
+            self.merge_stack(&mut retry);
+        } else if (*queue).is_null() && *bypassed >= max_bypass {
+            *queue = self.take_stack(&mut retry);
+            *bypassed = 0;
+        }
+
+        if !(*queue).is_null() {
+            let node = *queue;
+            *queue = *H::Node::next(node);
+            return node;
+        }
+
+        let mut head = self.head.load();
+        loop {
+            assert!(H::locked(head));
+
+            let top = H::top(head);
+            let replaced = if top.is_null() {
+                // Release the lock on an empty stack
+                self.head.replace(head, ptr::null_mut(), false)
+            } else {
+                // Pop off a nonempty stack and pass off the lock
+                atomic::fence(Ordering::Acquire);
+                self.head.replace(head, *H::Node::next(top), true)
+            };
+            match replaced {
+                Ok(()) => {
+                    if !top.is_null() {
+                        *bypassed = (*bypassed).saturating_add(1);
+                    }
+                    return top;
+                }
+                Err(newhead) => head = newhead,
+            }
+
+            retry();
+        }
+    }
+
+    /// Take every waiter off the stack and put them into the queue
+    /// behind all waiters of the same or higher priority.  Must hold
+    /// the lock.
+    unsafe fn merge_stack<F: FnMut()>(&self, retry: F) {
+        let mut node = self.take_stack(retry);
+        while !node.is_null() {
+            let next = *H::Node::next(node);
+
+            let mut link = self.queue.get();
+            while !(*link).is_null() && H::Node::priority(*link) >= H::Node::priority(node) {
+                link = H::Node::next(*link);
+            }
+            *H::Node::next(node) = *link;
+            *link = node;
+
+            node = next;
+        }
+    }
+
+    /// Take every waiter off the stack at once and return them oldest
+    /// first.  Must hold the lock.
+    unsafe fn take_stack<F: FnMut()>(&self, mut retry: F) -> *mut H::Node {
+        let mut head = self.head.load();
+        loop {
+            if H::top(head).is_null() {
+                return ptr::null_mut();
+            }
+            match self.head.replace(head, ptr::null_mut(), true) {
+                Ok(()) => break,
+                Err(newhead) => head = newhead,
+            }
+
+            retry();
+        }
+
+        atomic::fence(Ordering::Acquire);
+        let mut reversed = ptr::null_mut();
+        let mut node = H::top(head);
+        while !node.is_null() {
+            let next = *H::Node::next(node);
+            *H::Node::next(node) = reversed;
+            reversed = node;
+            node = next;
+        }
+        reversed
+    }
+}
@@ -11,245 +11,380 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
-use std::mem;
 use std::ptr;
-use std::sync::atomic;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::thread;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 
 use dontshare::DontShare;
-use sleepfast;
-use weakrand;
-
+use lock_api;
+
+#[cfg(not(feature = "node_arena"))]
+use aba::{Aba, AtomicAba};
+use backoff::{Backoff, Exponential};
+#[cfg(feature = "node_arena")]
+use node_arena;
+#[cfg(feature = "node_arena")]
+use node_arena::{Aba, AtomicAba};
+use parker::{DefaultParker, Instant, Parker};
+use raw_lock::RawLock;
+use stack::{DEFAULT_MAX_BYPASS, Link, Stack};
+use stats;
+#[cfg(feature = "stats")]
+use stats::Stats;
 use tts_mutex;
 
-const MAX_EXP: usize = 8;
-
 /// A queue lock like the CLH or MCS ones but that use a stack
 /// instead.  This algorithm is a bit hairy but is a fairly straight
 /// forward translation of the TLA+ specification under
 /// tla/StackLock.tla to use weak compare and swap and a Treiber
 /// Stack.
-pub struct RawMutex {
-    head: AtomicAba,
+///
+/// Unlocking always hands the lock straight to a popped waiter so
+/// the default fair unlock is the same as a normal one.
+///
+/// Being a stack the oldest waiter could be starved forever.  So
+/// after `max_bypass` waiters have been popped off the top the lock
+/// holder takes the whole stack, reverses it and serves those waiters
+/// oldest first before going back to the stack.  No waiter is
+/// overtaken by more than `max_bypass` later arrivals.
+///
+/// Waiters may also lock with a priority.  Once any has, the holder
+/// takes the whole stack on every unlock and merges it into the queue
+/// ordered by priority, first come first served among equals.  The
+/// highest priority waiter always gets the lock next and so low
+/// priority waiters can be starved.
+///
+/// A thread that loses a race to change the stack backs off according
+/// to `B` before trying again and so do waiters woken up to take the
+/// lock.  Waiters sleep on their nodes with the parker `P`.  Nothing
+/// here needs the standard library except timeouts, which allocate
+/// their node on the heap.
+///
+/// With the `node_arena` feature nodes are built in slots of a
+/// process wide arena instead and the head holds a slot index.
+pub struct RawMutex<B: Backoff = Exponential, P: Parker = DefaultParker> {
+    stack: Stack<AtomicAba<Node<B, P>>>,
+    max_bypass: usize,
+    realtime: bool,
+    // How many threads are in lock_with_priority with a nonzero
+    // priority and haven't got the lock yet
+    prioritized: AtomicUsize,
+    backoff: B,
+    stats: stats::Counters,
 }
-unsafe impl Send for RawMutex {}
-unsafe impl Sync for RawMutex {}
+unsafe impl<B: Backoff, P: Parker> Send for RawMutex<B, P> {}
+unsafe impl<B: Backoff, P: Parker> Sync for RawMutex<B, P> {}
 
-impl Default for RawMutex {
+impl<B: Backoff, P: Parker> Default for RawMutex<B, P> {
     fn default() -> Self {
-        Self::new()
+        Self::with_parker(B::INIT)
     }
 }
 
 impl RawMutex {
     #[inline]
-    pub fn new() -> Self {
-        RawMutex { head: AtomicAba::new(Aba::new(ptr::null_mut(), 0, false)) }
+    pub const fn new() -> Self {
+        Self::with_max_bypass(DEFAULT_MAX_BYPASS)
     }
 
-    pub fn lock(&self) {
-        let mut node = Node::new();
+    /// A lock that lets at most `max_bypass` later arrivals overtake
+    /// a waiter.  Zero makes the lock strictly first come first
+    /// served and `usize::MAX` makes it a pure stack.
+    #[inline]
+    pub const fn with_max_bypass(max_bypass: usize) -> Self {
+        RawMutex::build(Exponential::INIT, max_bypass, false)
+    }
 
-        let mut head = self.head.load(Ordering::Relaxed);
-        let mut counter = 0;
-        loop {
-            if head.locked() {
-                // On a locked stack push the node
-                *node.next = head.ptr();
-                let new = Aba::new(&mut node, head.tag().wrapping_add(1), true);
-
-                if let Err(newhead) = self.head
-                    .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
-                    head = newhead;
-                } else {
-                    break;
-                }
-            } else {
-                // Acquire an unlocked stack
-                let new = Aba::new(head.ptr(), head.tag().wrapping_add(1), true);
-
-                if let Err(newhead) = self.head
-                    .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
-                    head = newhead;
-                } else {
-                    return;
-                }
-            }
+    /// A strictly first come first served lock for real time
+    /// threads.  Nothing ever calls `sched_yield` or spins.  Waiters
+    /// go straight to sleep on their node's futex word and so can't
+    /// keep a lower priority holder off its CPU.
+    ///
+    /// Pushing a node and popping one are single compare and swaps
+    /// that are retried when another thread changed the stack in
+    /// between or the compare and swap failed spuriously.  That is
+    /// lock free but not wait free so a push has no hard bound on its
+    /// attempts, though with `n` threads using the lock it should
+    /// expect to need about `n` at worst.  Once pushed a waiter is
+    /// handed the lock after every waiter that pushed before it has
+    /// had its turn, so expect it to wait for about `n - 1` critical
+    /// sections each followed by a single futex wake up.  Without
+    /// priority inheritance it is still up to the scheduler to run the
+    /// holder, see `PiMutex` for that.
+    #[inline]
+    pub const fn realtime() -> Self {
+        RawMutex::build(Exponential::INIT, 0, true)
+    }
+}
 
-            thread::yield_now();
+impl<B: Backoff> RawMutex<B> {
+    #[inline]
+    pub const fn with_backoff(backoff: B) -> Self {
+        RawMutex::with_parker(backoff)
+    }
+}
 
-            let exp = if counter < MAX_EXP {
-                let old = counter;
-                counter = counter.wrapping_add(1);
-                1 << old
-            } else {
-                1 << MAX_EXP
-            };
-            let spins = weakrand::rand(1, exp);
+impl<B: Backoff, P: Parker> RawMutex<B, P> {
+    /// Like `with_backoff` but for a lock whose waiters sleep with a
+    /// parker other than the default, named in the type.
+    #[inline]
+    pub const fn with_parker(backoff: B) -> Self {
+        RawMutex::build(backoff, DEFAULT_MAX_BYPASS, false)
+    }
 
-            sleepfast::pause_times(spins as usize);
+    const fn build(backoff: B, max_bypass: usize, realtime: bool) -> Self {
+        RawMutex {
+            stack: Stack::new(AtomicAba::new(Aba::null())),
+            max_bypass,
+            realtime,
+            prioritized: AtomicUsize::new(0),
+            backoff,
+            stats: stats::Counters::new(),
         }
+    }
 
-        node.wait();
+    pub fn lock(&self) {
+        self.lock_with_priority(0);
     }
 
-    pub fn unlock(&self) {
-        unsafe {
-            let mut head = self.head.load(Ordering::Relaxed);
-            assert!(head.locked());
+    /// Lock ahead of every waiter with a lower priority.  A plain
+    /// lock has the lowest priority of zero.
+    pub fn lock_with_priority(&self, priority: u32) {
+        if priority != 0 {
+            self.prioritized.fetch_add(1, Ordering::SeqCst);
+        }
 
-            let mut counter = 0;
-            loop {
-                if head.ptr().is_null() {
-                    // Release the lock on an empty stack
-                    let new = Aba::new(ptr::null_mut(), head.tag().wrapping_add(1), false);
-                    if let Err(newhead) = self.head
-                        .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
-                        head = newhead;
-                    } else {
-                        break;
-                    }
-                } else {
-                    // Pop off a nonempty stack and pass off the lock
-                    atomic::fence(Ordering::Acquire);
-                    let next;
-                    {
-                        let head_ref = &mut *head.ptr();
-                        next = *head_ref.next;
-                    }
-                    let new = Aba::new(next, head.tag().wrapping_add(1), true);
-                    if let Err(newhead) = self.head
-                        .compare_exchange_weak(head, new, Ordering::SeqCst, Ordering::Relaxed) {
-                        head = newhead;
-                    } else {
-                        (*head.ptr()).signal();
-                        break;
-                    }
-                }
-                assert!(head.locked());
+        self.with_node(|node| unsafe {
+            (*node).priority = priority;
 
-                thread::yield_now();
+            if self.push(node) {
+                self.stats.sleep();
+                (*node).wait(self.realtime);
+            }
+        });
+
+        if priority != 0 {
+            self.prioritized.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        self.stack.try_lock()
+    }
 
-                let exp = if counter < MAX_EXP {
-                    let old = counter;
-                    counter = counter.wrapping_add(1);
-                    1 << old
-                } else {
-                    1 << MAX_EXP
-                };
-                let spins = weakrand::rand(1, exp);
+    /// Like lock but gives up once the deadline passes.  The node is
+    /// heap allocated, or given an arena slot of its own, so that a
+    /// waiter that times out can abandon it on the stack.  Ownership
+    /// of an abandoned node passes to whoever pops it.  Nodes still
+    /// only leave the stack by being popped by the lock holder so the
+    /// ABA tags work as before.
+    #[cfg(feature = "std")]
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        unsafe {
+            let node = self.alloc_node();
 
-                sleepfast::pause_times(spins as usize);
+            if !self.push(node) {
+                Self::free_node(node);
+                return true;
+            }
+            self.stats.sleep();
+
+            if !(*node).wait_until(deadline, self.realtime) {
+                if (*node)
+                    .state
+                    .compare_exchange(WAITING, ABANDONED, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_ok() {
+                    return false;
+                }
+                // Lost the race against unlock, the lock is ours and
+                // the signal is on its way.
+                (*node).wait(self.realtime);
             }
+
+            Self::free_node(node);
+            true
         }
     }
-}
 
-struct Node {
-    notifier: DontShare<tts_mutex::RawMutex>,
-    next: DontShare<*mut Node>,
-}
+    /// Without `std` there is no clock to make a deadline with.
+    #[cfg(not(feature = "std"))]
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        match deadline {}
+    }
 
-impl Node {
+    /// Pass `f` a fresh node on the calling thread's stack.
+    #[cfg(not(feature = "node_arena"))]
     #[inline]
-    fn new() -> Node {
-        Node {
-            notifier: DontShare::new(tts_mutex::RawMutex::new_locked()),
-            next: DontShare::new(ptr::null_mut()),
-        }
+    fn with_node<F: FnOnce(*mut Node<B, P>)>(&self, f: F) {
+        let mut node = Node::new(self.backoff);
+        f(&mut node);
     }
 
-    fn signal(&self) {
-        self.notifier.unlock();
+    /// Pass `f` a fresh node in the calling thread's arena slot.
+    #[cfg(feature = "node_arena")]
+    #[inline]
+    fn with_node<F: FnOnce(*mut Node<B, P>)>(&self, f: F) {
+        node_arena::with_local(Node::new(self.backoff), f);
     }
 
-    fn wait(&self) {
-        self.notifier.lock();
+    /// A fresh node that can outlive the call, for timed waits.
+    #[cfg(all(feature = "std", not(feature = "node_arena")))]
+    fn alloc_node(&self) -> *mut Node<B, P> {
+        Box::into_raw(Box::new(Node::new(self.backoff)))
     }
-}
-
-#[derive(Copy, Clone)]
-struct Aba {
-    ptr: u64,
-}
 
-// Because of pointer alignment to 128 bytes 7 end bits are always zero.
-const ZERO_BITS: u64 = 7;
+    #[cfg(all(feature = "std", not(feature = "node_arena")))]
+    unsafe fn free_node(node: *mut Node<B, P>) {
+        drop(Box::from_raw(node));
+    }
 
-const LOCKED_OFFSET: u64 = 0;
-const LOCKED_SIZE: u64 = 1;
+    #[cfg(feature = "node_arena")]
+    fn alloc_node(&self) -> *mut Node<B, P> {
+        node_arena::alloc(Node::new(self.backoff))
+    }
 
-const TAG_OFFSET: u64 = LOCKED_SIZE;
-const TAG_SIZE: u64 = 22;
+    #[cfg(feature = "node_arena")]
+    unsafe fn free_node(node: *mut Node<B, P>) {
+        node_arena::free(node);
+    }
 
-const PTR_OFFSET: u64 = LOCKED_SIZE + TAG_SIZE;
-const PTR_SIZE: u64 = 41;
+    /// Either acquire the lock and return false or push the node and
+    /// return true.
+    fn push(&self, node: *mut Node<B, P>) -> bool {
+        let mut counter = 0;
+        self.stack.push(node, || self.backoff(&mut counter))
+    }
 
-impl Aba {
-    #[inline]
-    fn new(node: *mut Node, tag: u32, locked: bool) -> Self {
+    pub fn unlock(&self) {
         unsafe {
-            let lock_bit: u64 = if locked { 1 } else { 0 };
-            let node_bits: u64 = mem::transmute(node);
-            let tag_bits: u64 = (tag & ((1 << TAG_SIZE) - 1)) as u64;
-            Aba {
-                ptr: lock_bit << LOCKED_OFFSET | tag_bits << TAG_OFFSET |
-                     (node_bits >> ZERO_BITS) << PTR_OFFSET,
+            loop {
+                let node = self.next_waiter();
+                if node.is_null() {
+                    break;
+                }
+                if (*node)
+                    .state
+                    .compare_exchange(WAITING, SIGNALLED, Ordering::SeqCst, Ordering::Relaxed)
+                    .is_ok() {
+                    // The waiter may free the lock once signalled
+                    self.stats.wake();
+                    self.stats.handoff();
+                    (*node).signal();
+                    break;
+                }
+                // The waiter timed out and left the node to us.  We
+                // still hold the lock so move on to the next one.
+                #[cfg(feature = "std")]
+                Self::free_node(node);
             }
         }
     }
 
-    fn get(&self, offset: u64, size: u64) -> u64 {
-        (self.ptr >> offset) & ((1 << size) - 1)
+    /// Take the next waiter to pass the lock to.  If there is none
+    /// release the lock and return null.  Must hold the lock.
+    unsafe fn next_waiter(&self) -> *mut Node<B, P> {
+        let prioritized = self.prioritized.load(Ordering::SeqCst) != 0;
+        let mut counter = 0;
+        self.stack.next_waiter(self.max_bypass, prioritized, || self.backoff(&mut counter))
+    }
+
+    // Back off after losing a race on the head.  In real time mode
+    // just retry straight away, the thread that won has already made
+    // progress.
+    fn backoff(&self, counter: &mut usize) {
+        if self.realtime {
+            return;
+        }
+
+        self.stats.spins(1);
+
+        self.backoff.backoff::<P>(*counter);
+
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+unsafe impl<B: Backoff, P: Parker> RawLock for RawMutex<B, P> {
+    const INIT: Self = RawMutex::with_parker(B::INIT);
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
     }
 
-    fn locked(&self) -> bool {
-        self.get(LOCKED_OFFSET, LOCKED_SIZE) != 0
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
     }
 
-    fn ptr(&self) -> *mut Node {
-        let node_bits = self.get(PTR_OFFSET, PTR_SIZE) << ZERO_BITS;
-        node_bits as *mut Node
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
     }
-    fn tag(&self) -> u32 {
-        let tag_bits = self.get(TAG_OFFSET, TAG_SIZE);
-        tag_bits as u32
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
     }
-}
-impl PartialEq for Aba {
-    fn eq(&self, other: &Aba) -> bool {
-        self.ptr == other.ptr
+
+    fn lock_with_priority(&self, priority: u32) {
+        RawMutex::lock_with_priority(self, priority);
+    }
+
+    #[cfg(feature = "stats")]
+    fn add_stats(&self, stats: &mut Stats) {
+        self.stats.add_to(stats);
     }
 }
-struct AtomicAba {
-    ptr: AtomicU64,
+
+const WAITING: u32 = 0;
+const SIGNALLED: u32 = 1;
+#[cfg(feature = "std")]
+const ABANDONED: u32 = 2;
+
+struct Node<B: Backoff, P: Parker> {
+    notifier: DontShare<tts_mutex::RawMutex<B, P>>,
+    next: DontShare<*mut Node<B, P>>,
+    state: AtomicU32,
+    priority: u32,
+}
+
+impl<B: Backoff, P: Parker> Link for Node<B, P> {
+    unsafe fn next(node: *mut Self) -> *mut *mut Self {
+        &mut *(*node).next
+    }
+
+    unsafe fn priority(node: *mut Self) -> u32 {
+        (*node).priority
+    }
 }
-impl AtomicAba {
+
+impl<B: Backoff, P: Parker> Node<B, P> {
     #[inline]
-    fn new(ptr: Aba) -> Self {
-        AtomicAba { ptr: AtomicU64::new(ptr.ptr) }
-    }
-
-    fn load(&self, ordering: Ordering) -> Aba {
-        Aba { ptr: self.ptr.load(ordering) }
-    }
-
-    fn compare_exchange_weak(&self,
-                             old: Aba,
-                             new: Aba,
-                             success: Ordering,
-                             fail: Ordering)
-                             -> Result<Aba, Aba> {
-        // Test and test and set optimization
-        let mut dblcheck = self.ptr.load(fail);
-        if dblcheck == old.ptr {
-            match self.ptr
-                .compare_exchange_weak(old.ptr, new.ptr, success, fail) {
-                Err(x) => dblcheck = x,
-                Ok(x) => return Ok(Aba { ptr: x }),
-            }
+    fn new(backoff: B) -> Self {
+        Node {
+            notifier: DontShare::new(tts_mutex::RawMutex::new_locked(backoff)),
+            next: DontShare::new(ptr::null_mut()),
+            state: AtomicU32::new(WAITING),
+            priority: 0,
+        }
+    }
+
+    fn signal(&self) {
+        self.notifier.unlock();
+    }
+
+    fn wait(&self, realtime: bool) {
+        if realtime {
+            self.notifier.sleep_lock_until(None);
+        } else {
+            self.notifier.lock();
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn wait_until(&self, deadline: Instant, realtime: bool) -> bool {
+        if realtime {
+            self.notifier.sleep_lock_until(Some(deadline))
+        } else {
+            self.notifier.try_lock_until(deadline)
         }
-        Err(Aba { ptr: dblcheck })
     }
 }
@@ -0,0 +1,271 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+//! Contention statistics, enabled with the `stats` feature.  Without
+//! it the counters are empty and counting does nothing.
+
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "stats")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "stats")]
+const BUCKETS: usize = 64;
+
+/// A snapshot of what happened to a `Mutex` so far.
+#[cfg(feature = "stats")]
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Acquisitions of the test and test and set lock without
+    /// sleeping.
+    pub fast_path: u64,
+    /// Times around the spin loops.
+    pub spins: u64,
+    /// Times a thread stopped spinning early because the holder
+    /// looked like it was not running.
+    pub descheduled: u64,
+    /// Times a thread gave up spinning and queued on a stack lock.
+    pub fallbacks: u64,
+    /// How often each stack lock was picked by `sched_getcpu`.
+    pub shards: Vec<u64>,
+    /// Times a thread went to sleep on a futex.
+    pub sleeps: u64,
+    /// Times a thread woke up after going to sleep on a futex.
+    pub wakes: u64,
+    /// Times the lock was passed directly to a waiter.
+    pub handoffs: u64,
+    /// How long it took to get the lock.
+    pub wait_time: Histogram,
+    /// How long the lock was held.
+    pub hold_time: Histogram,
+}
+
+/// Durations by power of two nanoseconds.  Bucket `i` counts
+/// durations from `2^i` up to `2^(i + 1)` nanoseconds, bucket zero
+/// also counts zero.
+#[cfg(feature = "stats")]
+#[derive(Clone)]
+pub struct Histogram {
+    pub buckets: [u64; BUCKETS],
+}
+
+#[cfg(feature = "stats")]
+impl Histogram {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+#[cfg(feature = "stats")]
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram { buckets: [0; BUCKETS] }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl ::std::fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let used = BUCKETS - self.buckets.iter().rev().take_while(|&&n| n == 0).count();
+        f.debug_list().entries(&self.buckets[..used]).finish()
+    }
+}
+
+/// Event counters kept by the raw locks.
+#[cfg(feature = "stats")]
+pub struct Counters {
+    fast_path: AtomicU64,
+    spins: AtomicU64,
+    descheduled: AtomicU64,
+    fallbacks: AtomicU64,
+    shards: [AtomicU64; 2],
+    sleeps: AtomicU64,
+    wakes: AtomicU64,
+    handoffs: AtomicU64,
+}
+
+#[cfg(feature = "stats")]
+impl Counters {
+    pub const fn new() -> Counters {
+        Counters {
+            fast_path: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+            descheduled: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+            shards: [AtomicU64::new(0), AtomicU64::new(0)],
+            sleeps: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            handoffs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn fast_path(&self) {
+        self.fast_path.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn spins(&self, spins: usize) {
+        self.spins.fetch_add(spins as u64, Ordering::Relaxed);
+    }
+
+    pub fn descheduled(&self) {
+        self.descheduled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fallback(&self, shard: usize) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+        self.shards[shard].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sleep(&self) {
+        self.sleeps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn wake(&self) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handoff(&self) {
+        self.handoffs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_to(&self, stats: &mut Stats) {
+        stats.fast_path += self.fast_path.load(Ordering::Relaxed);
+        stats.spins += self.spins.load(Ordering::Relaxed);
+        stats.descheduled += self.descheduled.load(Ordering::Relaxed);
+        stats.fallbacks += self.fallbacks.load(Ordering::Relaxed);
+        stats.sleeps += self.sleeps.load(Ordering::Relaxed);
+        stats.wakes += self.wakes.load(Ordering::Relaxed);
+        stats.handoffs += self.handoffs.load(Ordering::Relaxed);
+
+        if stats.shards.len() < self.shards.len() {
+            stats.shards.resize(self.shards.len(), 0);
+        }
+        for (total, shard) in stats.shards.iter_mut().zip(self.shards.iter()) {
+            *total += shard.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+pub struct Counters;
+
+#[cfg(not(feature = "stats"))]
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+impl Counters {
+    #[inline]
+    pub const fn new() -> Counters {
+        Counters
+    }
+
+    #[inline]
+    pub fn fast_path(&self) {}
+
+    #[inline]
+    pub fn spins(&self, _spins: usize) {}
+
+    #[inline]
+    pub fn descheduled(&self) {}
+
+    #[inline]
+    pub fn fallback(&self, _shard: usize) {}
+
+    #[inline]
+    pub fn sleep(&self) {}
+
+    #[inline]
+    pub fn wake(&self) {}
+
+    #[inline]
+    pub fn handoff(&self) {}
+}
+
+/// The wait and hold times of a `Mutex`.
+#[cfg(feature = "stats")]
+pub struct Times {
+    wait_time: [AtomicU64; BUCKETS],
+    hold_time: [AtomicU64; BUCKETS],
+}
+
+#[cfg(feature = "stats")]
+impl Times {
+    pub const fn new() -> Times {
+        Times {
+            wait_time: [const { AtomicU64::new(0) }; BUCKETS],
+            hold_time: [const { AtomicU64::new(0) }; BUCKETS],
+        }
+    }
+
+    pub fn waited(&self, since: &Timer) {
+        Self::record(&self.wait_time, since.0.elapsed());
+    }
+
+    pub fn held(&self, since: &Timer) {
+        Self::record(&self.hold_time, since.0.elapsed());
+    }
+
+    pub fn add_to(&self, stats: &mut Stats) {
+        for (total, bucket) in stats.wait_time.buckets.iter_mut().zip(self.wait_time.iter()) {
+            *total += bucket.load(Ordering::Relaxed);
+        }
+        for (total, bucket) in stats.hold_time.buckets.iter_mut().zip(self.hold_time.iter()) {
+            *total += bucket.load(Ordering::Relaxed);
+        }
+    }
+
+    fn record(histogram: &[AtomicU64; BUCKETS], time: Duration) {
+        let nanos = time.as_secs()
+            .saturating_mul(1000000000)
+            .saturating_add(time.subsec_nanos() as u64);
+        let bucket = 63 - (nanos | 1).leading_zeros() as usize;
+        histogram[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+pub struct Times;
+
+#[cfg(not(feature = "stats"))]
+impl Times {
+    #[inline]
+    pub const fn new() -> Times {
+        Times
+    }
+
+    #[inline]
+    pub fn waited(&self, _since: &Timer) {}
+
+    #[inline]
+    pub fn held(&self, _since: &Timer) {}
+}
+
+/// When something started.
+#[cfg(feature = "stats")]
+pub struct Timer(Instant);
+
+#[cfg(feature = "stats")]
+impl Timer {
+    pub fn start() -> Timer {
+        Timer(Instant::now())
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+pub struct Timer;
+
+#[cfg(not(feature = "stats"))]
+impl Timer {
+    #[inline]
+    pub fn start() -> Timer {
+        Timer
+    }
+}
@@ -11,41 +11,82 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
 // implied.  See the License for the specific language governing
 // permissions and limitations under the License.
-use std::mem;
+use lock_api;
+
+use std::marker::PhantomData;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::thread;
-use sleepfast;
-use weakrand;
 
-const INITIAL_LOOPS: usize = 20;
-const NUM_LOOPS: usize = 20;
-const MAX_EXP: usize = 8;
+use backoff::{Backoff, Exponential};
+use parker::{DefaultParker, Instant, Parker};
+use raw_lock::RawLock;
+use stats;
+#[cfg(feature = "stats")]
+use stats::Stats;
 
 const UNLOCKED: u32 = 0;
 const LOCKED: u32 = 1;
 const LOCKED_WITH_WAITER: u32 = 2;
+// Released by a fair unlock.  A plain try_lock can't take it, only a
+// thread on the contended path can.  That is whichever waiter gets
+// there first and not necessarily the one that was woken.
+const HANDOFF: u32 = 3;
 
 /// This is basically Ulrich-Drepper's futexes are tricky futex lock
-pub struct RawMutex {
+///
+/// Both before first going to sleep and after every wake up a waiter
+/// backs off according to `B` for every attempt up to `B::spins`.
+/// Waiters sleep on the word with the parker `P`.
+pub struct RawMutex<B: Backoff = Exponential, P: Parker = DefaultParker> {
     val: AtomicU32,
+    backoff: B,
+    stats: stats::Counters,
+    parker: PhantomData<P>,
 }
-const FUTEX_WAIT_PRIVATE: usize = 128;
-const FUTEX_WAKE_PRIVATE: usize = 1 | 128;
 
-impl Default for RawMutex {
+impl<B: Backoff, P: Parker> Default for RawMutex<B, P> {
     fn default() -> Self {
-        Self::new()
+        Self::with_parker(B::INIT)
     }
 }
 
 impl RawMutex {
     #[inline]
-    pub fn new() -> RawMutex {
-        RawMutex { val: AtomicU32::new(UNLOCKED) }
+    pub const fn new() -> RawMutex {
+        RawMutex::with_backoff(Exponential::INIT)
+    }
+}
+
+impl<B: Backoff> RawMutex<B> {
+    #[inline]
+    pub const fn with_backoff(backoff: B) -> Self {
+        RawMutex::with_parker(backoff)
+    }
+}
+
+impl<B: Backoff, P: Parker> RawMutex<B, P> {
+    /// Like `with_backoff` but for a lock whose waiters sleep with a
+    /// parker other than the default, named in the type.
+    #[inline]
+    pub const fn with_parker(backoff: B) -> Self {
+        RawMutex {
+            val: AtomicU32::new(UNLOCKED),
+            backoff,
+            stats: stats::Counters::new(),
+            parker: PhantomData,
+        }
+    }
+
+    pub const fn new_locked(backoff: B) -> Self {
+        RawMutex {
+            val: AtomicU32::new(LOCKED),
+            backoff,
+            stats: stats::Counters::new(),
+            parker: PhantomData,
+        }
     }
 
-    pub fn new_locked() -> RawMutex {
-        RawMutex { val: AtomicU32::new(LOCKED) }
+    pub fn is_locked(&self) -> bool {
+        self.val.load(Ordering::Relaxed) != UNLOCKED
     }
 
     pub fn try_lock(&self) -> bool {
@@ -59,78 +100,193 @@ impl RawMutex {
     }
 
     pub fn lock(&self) {
+        self.lock_until(None);
+    }
+
+    /// Like lock but gives up once the deadline passes.
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.lock_until(Some(deadline))
+    }
+
+    fn lock_until(&self, deadline: Option<Instant>) -> bool {
         {
             let mut counter = 0;
             loop {
                 if self.try_lock() {
-                    return;
+                    self.stats.fast_path();
+                    self.stats.spins(counter);
+                    return true;
                 }
 
-                if counter > INITIAL_LOOPS {
+                if counter > self.backoff.spins() {
+                    self.stats.spins(counter);
                     break;
                 }
 
-                thread::yield_now();
-
-                let exp = if counter < MAX_EXP {
-                    1 << counter
-                } else {
-                    1 << MAX_EXP
-                };
+                self.backoff.backoff::<P>(counter);
 
                 counter = counter.wrapping_add(1);
-
-                let spins = weakrand::rand(1, exp);
-
-                sleepfast::pause_times(spins as usize);
             }
         }
 
-        if self.val.load(Ordering::Relaxed) != LOCKED_WITH_WAITER &&
-           UNLOCKED == self.val.swap(LOCKED_WITH_WAITER, Ordering::SeqCst) {
-            return;
+        self.lock_contended_until(deadline)
+    }
+
+    /// Lock without first spinning and always leave the lock marked
+    /// as having waiters.  Used by threads that were requeued onto
+    /// this lock's futex word and so might have company.
+    pub fn lock_contended(&self) {
+        self.lock_contended_until(None);
+    }
+
+    /// Like `lock_contended` but gives up once the deadline passes.
+    pub fn lock_contended_until(&self, deadline: Option<Instant>) -> bool {
+        if self.grab() {
+            return true;
         }
 
         'big_loop: loop {
-            unsafe {
-                let val_ptr: usize = mem::transmute(&self.val);
-                syscall!(FUTEX, val_ptr, FUTEX_WAIT_PRIVATE, LOCKED_WITH_WAITER, 0);
+            self.stats.sleep();
+            if !P::wait(&self.val, LOCKED_WITH_WAITER, deadline) {
+                // The lock may be left marked as having a waiter.
+                // That only costs the next unlock a spurious wakeup.
+                return false;
             }
+            self.stats.wake();
 
             let mut counter = 0;
             loop {
-                if self.val.load(Ordering::Relaxed) != LOCKED_WITH_WAITER &&
-                   UNLOCKED == self.val.swap(LOCKED_WITH_WAITER, Ordering::SeqCst) {
+                if self.grab() {
+                    self.stats.spins(counter);
                     break 'big_loop;
                 }
 
-                if counter > NUM_LOOPS {
+                if counter > self.backoff.spins() {
+                    self.stats.spins(counter);
                     break;
                 }
 
-                thread::yield_now();
-
-                let exp = if counter < MAX_EXP {
-                    1 << counter
-                } else {
-                    1 << MAX_EXP
-                };
+                self.backoff.backoff::<P>(counter);
 
                 counter = counter.wrapping_add(1);
+            }
+        }
+        true
+    }
 
-                let spins = weakrand::rand(1, exp);
+    /// Lock by going straight to sleep on the futex until the lock
+    /// is free, never spinning or yielding.
+    pub fn sleep_lock_until(&self, deadline: Option<Instant>) -> bool {
+        while !self.grab() {
+            self.stats.sleep();
+            if !P::wait(&self.val, LOCKED_WITH_WAITER, deadline) {
+                return false;
+            }
+            self.stats.wake();
+        }
+        true
+    }
 
-                sleepfast::pause_times(spins as usize);
+    fn grab(&self) -> bool {
+        if self.val.load(Ordering::Relaxed) == LOCKED_WITH_WAITER {
+            return false;
+        }
+        match self.val.swap(LOCKED_WITH_WAITER, Ordering::SeqCst) {
+            UNLOCKED => true,
+            HANDOFF => {
+                self.stats.handoff();
+                true
             }
+            _ => false,
         }
     }
 
+    /// The word that waiters sleep on.
+    pub fn futex_word(&self) -> &AtomicU32 {
+        &self.val
+    }
+
+    // Once the lock is released the next holder may free it so only
+    // its address is used to wake a waiter.  Wakeups and handoffs are
+    // counted by the waiters instead.
     pub fn unlock(&self) {
         if self.val.swap(UNLOCKED, Ordering::SeqCst) == LOCKED_WITH_WAITER {
-            unsafe {
-                let val_ptr: usize = mem::transmute(&self.val);
-                syscall!(FUTEX, val_ptr, FUTEX_WAKE_PRIVATE, 1);
+            P::wake(&self.val, 1);
+        }
+    }
+
+    /// Unlock but if there is a sleeping waiter keep threads that are
+    /// still spinning from barging in ahead of the waiters.  Which
+    /// waiter gets the lock is up to whoever reaches it first.
+    pub fn unlock_fair(&self) {
+        let mut val = self.val.load(Ordering::Relaxed);
+        loop {
+            let new = if val == LOCKED_WITH_WAITER {
+                HANDOFF
+            } else {
+                UNLOCKED
+            };
+            match self.val.compare_exchange_weak(val, new, Ordering::SeqCst, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(newval) => val = newval,
             }
         }
+        if val != LOCKED_WITH_WAITER {
+            return;
+        }
+        if P::wake(&self.val, 1) == 0 {
+            // Everybody gave up waiting.  Take the handoff back
+            // unless some other waiter got it first.
+            let _ = self.val
+                .compare_exchange(HANDOFF, UNLOCKED, Ordering::SeqCst, Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn add_stats(&self, stats: &mut Stats) {
+        self.stats.add_to(stats);
+    }
+}
+
+unsafe impl<B: Backoff, P: Parker> RawLock for RawMutex<B, P> {
+    const INIT: Self = RawMutex::with_parker(B::INIT);
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawMutex::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        RawMutex::try_lock(self)
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawMutex::try_lock_until(self, deadline)
+    }
+
+    unsafe fn unlock(&self) {
+        RawMutex::unlock(self);
+    }
+
+    unsafe fn unlock_fair(&self) {
+        RawMutex::unlock_fair(self);
+    }
+
+    fn futex_word(&self) -> Option<&AtomicU32> {
+        if P::FUTEX {
+            Some(RawMutex::futex_word(self))
+        } else {
+            None
+        }
+    }
+
+    fn lock_requeued(&self) {
+        RawMutex::lock_contended(self);
+    }
+
+    #[cfg(feature = "stats")]
+    fn add_stats(&self, stats: &mut Stats) {
+        RawMutex::add_stats(self, stats);
     }
 }
@@ -0,0 +1,199 @@
+// Copyright 2017 Steven Stewart-Gallus
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or
+// implied.  See the License for the specific language governing
+// permissions and limitations under the License.
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::asm;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64;
+
+use stack::Head;
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the wide_aba feature needs the x86-64 cmpxchg16b instruction");
+
+/// A whole pointer to a node packed together with a lock bit and a
+/// 64 bit tag that is bumped on every change so a weak compare and
+/// swap can't be fooled by a node that was popped and pushed back
+/// again.
+///
+/// Unlike the packed 64 bit head this works with any user address,
+/// including the 57 bit ones of five level paging, and the tag never
+/// wraps in practice.  The lock bit is kept in the bottom bit of the
+/// pointer so nodes only need to be aligned to 2 bytes.
+pub struct Aba<N> {
+    bits: u128,
+    _phantom: PhantomData<*mut N>,
+}
+impl<N> Clone for Aba<N> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<N> Copy for Aba<N> {}
+
+const LOCKED_BIT: u64 = 1;
+
+const TAG_OFFSET: u32 = 64;
+
+impl<N> Aba<N> {
+    /// An empty unlocked stack, for static initializers.
+    #[inline]
+    pub const fn null() -> Self {
+        Aba {
+            bits: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn new(node: *mut N, tag: u64, locked: bool) -> Self {
+        let lock_bit: u64 = if locked { LOCKED_BIT } else { 0 };
+        let node_bits = node as usize as u64;
+        Aba {
+            bits: (tag as u128) << TAG_OFFSET | (node_bits | lock_bit) as u128,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn from_bits(bits: u128) -> Self {
+        Aba {
+            bits,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn locked(&self) -> bool {
+        self.bits as u64 & LOCKED_BIT != 0
+    }
+
+    pub fn ptr(&self) -> *mut N {
+        (self.bits as u64 & !LOCKED_BIT) as *mut N
+    }
+
+    pub fn tag(&self) -> u64 {
+        (self.bits >> TAG_OFFSET) as u64
+    }
+}
+impl<N> PartialEq for Aba<N> {
+    fn eq(&self, other: &Aba<N>) -> bool {
+        self.bits == other.bits
+    }
+}
+
+/// Changed with `cmpxchg16b`, which every x86-64 processor but the
+/// very first few has.  There is no plain 128 bit atomic load so
+/// loads are a compare and swap as well and take the cache line for
+/// writing.  Being locked instructions every operation is
+/// sequentially consistent whatever ordering is asked for.
+#[repr(align(16))]
+pub struct AtomicAba<N> {
+    bits: UnsafeCell<u128>,
+    _phantom: PhantomData<*mut N>,
+}
+impl<N> AtomicAba<N> {
+    #[inline]
+    pub const fn new(ptr: Aba<N>) -> Self {
+        AtomicAba {
+            bits: UnsafeCell::new(ptr.bits),
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn load(&self, _ordering: Ordering) -> Aba<N> {
+        // Swapping zero for zero changes nothing whatever the
+        // comparison finds
+        Aba::from_bits(self.cmpxchg16b(0, 0))
+    }
+
+    pub fn compare_exchange_weak(&self,
+                                 old: Aba<N>,
+                                 new: Aba<N>,
+                                 _success: Ordering,
+                                 _fail: Ordering)
+                                 -> Result<Aba<N>, Aba<N>> {
+        let prev = self.cmpxchg16b(old.bits, new.bits);
+        if prev == old.bits {
+            Ok(Aba::from_bits(prev))
+        } else {
+            Err(Aba::from_bits(prev))
+        }
+    }
+
+    // Returns the previous value, which equals `old` only if the
+    // swap happened.  The instruction wants the low half of the new
+    // value in rbx, which can't be named as an operand because LLVM
+    // may need it as a base pointer, so it is swapped in and back out
+    // around the instruction.  In functions that don't need it the
+    // register allocator does hand out rbx for a plain register
+    // operand, so the address goes in a fixed register that the swap
+    // can't clobber.
+    fn cmpxchg16b(&self, old: u128, new: u128) -> u128 {
+        assert!(has_cmpxchg16b(), "the wide_aba feature needs a CPU with cmpxchg16b");
+        let prev_low: u64;
+        let prev_high: u64;
+        unsafe {
+            asm!("xchg {new_low}, rbx",
+                 "lock cmpxchg16b xmmword ptr [rdi]",
+                 "mov rbx, {new_low}",
+                 in("rdi") self.bits.get(),
+                 new_low = inout(reg) new as u64 => _,
+                 in("rcx") (new >> 64) as u64,
+                 inout("rax") old as u64 => prev_low,
+                 inout("rdx") (old >> 64) as u64 => prev_high,
+                 options(nostack));
+        }
+        (prev_high as u128) << 64 | prev_low as u128
+    }
+}
+
+// The earliest x86-64 CPUs lack the instruction.  CPUID reports it in
+// bit 13 of ECX for leaf 1.  Zero until checked, then one plus the
+// answer.
+fn has_cmpxchg16b() -> bool {
+    static HAS_CMPXCHG16B: AtomicU32 = AtomicU32::new(0);
+
+    let cached = HAS_CMPXCHG16B.load(Ordering::Relaxed);
+    if cached != 0 {
+        return cached == 2;
+    }
+    let has = x86_64::__cpuid(1).ecx & (1 << 13) != 0;
+    HAS_CMPXCHG16B.store(1 + has as u32, Ordering::Relaxed);
+    has
+}
+
+impl<N> Head for AtomicAba<N> {
+    type Node = N;
+    type Word = Aba<N>;
+
+    fn load(&self) -> Aba<N> {
+        AtomicAba::load(self, Ordering::Relaxed)
+    }
+
+    fn top(word: Aba<N>) -> *mut N {
+        word.ptr()
+    }
+
+    fn locked(word: Aba<N>) -> bool {
+        word.locked()
+    }
+
+    fn replace(&self, old: Aba<N>, top: *mut N, locked: bool) -> Result<(), Aba<N>> {
+        let new = Aba::new(top, old.tag().wrapping_add(1), locked);
+        self.compare_exchange_weak(old, new, Ordering::SeqCst, Ordering::Relaxed)
+            .map(|_| ())
+    }
+}
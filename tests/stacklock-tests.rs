@@ -1,9 +1,20 @@
+extern crate libc;
+extern crate lock_api;
 extern crate stacklock;
 
-use stacklock::Mutex;
+use stacklock::{AsyncMutex, Backoff, Condvar, Deadline, Exponential, Fixed, FutexMutex, FutexParker,
+                Hybrid, Mutex, MutexGuard, Parker, PiMutex, PlainMutex, RawLock, RawMutex,
+                RobustLockError, RobustMutex, RobustMutexGuard, RwLock, SharedMutex, SpinOnly,
+                SpinParker, StackMutex, YieldOnly};
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::ptr;
 use std::sync::{Arc, Barrier};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Wake, Waker};
 use std::thread;
+use std::time::{Duration, Instant};
 
 #[test]
 fn test_as_lock() {
@@ -44,12 +55,12 @@ fn test_race() {
             barrier_ref.wait();
 
             for _ in 0..1000 {
-                let _val = lock_ref.lock();
+                let _val = lock_ref.lock().unwrap();
                 for _ in 0..20 {
                     let prev = racer_ref.swap(true, Ordering::Relaxed);
-                    assert_eq!(prev, false);
+                    assert!(!prev);
                     let val = racer_ref.swap(false, Ordering::Relaxed);
-                    assert_eq!(val, true);
+                    assert!(val);
                 }
             }
         });
@@ -60,3 +71,1305 @@ fn test_race() {
         child.join().unwrap();
     }
 }
+
+#[test]
+fn test_try_lock_for() {
+    let lock = Arc::new(Mutex::new(()));
+
+    let guard = lock.lock().unwrap();
+
+    let mut children = Vec::new();
+    for _ in 0..20 {
+        let lock_ref = lock.clone();
+
+        let child = thread::spawn(move || {
+            lock_ref.try_lock_for(Duration::from_millis(50)).is_err()
+        });
+        children.push(child);
+    }
+
+    for child in children {
+        assert!(child.join().unwrap());
+    }
+
+    drop(guard);
+
+    assert!(lock.try_lock_for(Duration::from_millis(50)).is_ok());
+}
+
+#[test]
+fn test_timed_race() {
+    let num = 20;
+
+    let lock = Arc::new(Mutex::new(()));
+    let racer = Arc::new(AtomicBool::new(false));
+    let start = Arc::new(Barrier::new(num));
+
+    let mut children = Vec::new();
+    for ii in 0..num {
+        let lock_ref = lock.clone();
+        let racer_ref = racer.clone();
+        let barrier_ref = start.clone();
+
+        let child = thread::spawn(move || {
+            barrier_ref.wait();
+
+            for jj in 0..1000 {
+                let _val = if (ii + jj) % 2 == 0 {
+                    lock_ref.lock().unwrap()
+                } else {
+                    match lock_ref.try_lock_for(Duration::new(0, 1000)) {
+                        Ok(val) => val,
+                        Err(_) => continue,
+                    }
+                };
+                for _ in 0..20 {
+                    let prev = racer_ref.swap(true, Ordering::Relaxed);
+                    assert!(!prev);
+                    let val = racer_ref.swap(false, Ordering::Relaxed);
+                    assert!(val);
+                }
+            }
+        });
+        children.push(child);
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+}
+
+#[test]
+fn test_poison() {
+    let lock = Arc::new(Mutex::new(0));
+
+    let lock_ref = lock.clone();
+    let result = thread::spawn(move || {
+            let mut val = lock_ref.lock().unwrap();
+            *val = 1;
+            panic!("poison the lock");
+        })
+        .join();
+    assert!(result.is_err());
+
+    assert!(lock.is_poisoned());
+    match lock.lock() {
+        Ok(_) => panic!("lock was not poisoned"),
+        Err(err) => assert_eq!(*err.into_inner(), 1),
+    }
+    assert!(lock.try_lock_for(Duration::from_millis(1)).is_err());
+
+    lock.clear_poison();
+    assert!(!lock.is_poisoned());
+    assert_eq!(*lock.lock().unwrap(), 1);
+}
+
+static CACHE: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+static STACK_CACHE: Mutex<u32, StackMutex> = Mutex::with_raw_lock(StackMutex::new(), 0);
+
+#[test]
+fn test_static() {
+    let mut children = Vec::new();
+    for ii in 0..20 {
+        children.push(thread::spawn(move || {
+            CACHE.lock().unwrap().push(ii);
+            *STACK_CACHE.lock().unwrap() += 1;
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+
+    let mut cache = CACHE.lock().unwrap();
+    cache.sort();
+    assert_eq!(*cache, (0..20).collect::<Vec<u8>>());
+    assert_eq!(*STACK_CACHE.lock().unwrap(), 20);
+}
+
+#[test]
+fn test_plain_mutex() {
+    let lock = Arc::new(PlainMutex::new(0));
+
+    let lock_ref = lock.clone();
+    let result = thread::spawn(move || {
+            *lock_ref.lock() = 1;
+            let _guard = lock_ref.lock();
+            panic!("panic holding the lock");
+        })
+        .join();
+    assert!(result.is_err());
+
+    assert_eq!(*lock.lock(), 1);
+    assert!(lock.try_lock_for(Duration::from_millis(1)).is_some());
+}
+
+#[test]
+fn test_condvar_notify_one() {
+    let num = 20;
+
+    let pair = Arc::new((Mutex::new(0), Condvar::new()));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let pair_ref = pair.clone();
+
+        let child = thread::spawn(move || {
+            let (ref lock, ref cvar) = *pair_ref;
+            for _ in 0..100 {
+                let mut val = cvar.wait_while(lock.lock().unwrap(), |val| *val == 0).unwrap();
+                *val -= 1;
+            }
+        });
+        children.push(child);
+    }
+
+    {
+        let (ref lock, ref cvar) = *pair;
+        for _ in 0..num * 100 {
+            *lock.lock().unwrap() += 1;
+            cvar.notify_one();
+        }
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(*pair.0.lock().unwrap(), 0);
+}
+
+#[test]
+fn test_condvar_notify_all() {
+    let num = 20;
+
+    let pair = Arc::new((Mutex::new(false), Condvar::new()));
+    let woken = Arc::new(AtomicUsize::new(0));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let pair_ref = pair.clone();
+        let woken_ref = woken.clone();
+
+        let child = thread::spawn(move || {
+            let (ref lock, ref cvar) = *pair_ref;
+            let mut go = lock.lock().unwrap();
+            while !*go {
+                go = cvar.wait(go).unwrap();
+            }
+            woken_ref.fetch_add(1, Ordering::Relaxed);
+        });
+        children.push(child);
+    }
+
+    {
+        let (ref lock, ref cvar) = *pair;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(woken.load(Ordering::Relaxed), num);
+}
+
+#[test]
+fn test_condvar_wait_timeout() {
+    let lock = Mutex::new(());
+    let cvar = Condvar::new();
+
+    let guard = lock.lock().unwrap();
+    let (_guard, result) = cvar.wait_timeout(guard, Duration::from_millis(10)).unwrap();
+    assert!(result.timed_out());
+}
+
+#[test]
+fn test_rwlock_race() {
+    let num = 20;
+
+    let lock = Arc::new(RwLock::new(()));
+    let writing = Arc::new(AtomicBool::new(false));
+    let readers = Arc::new(AtomicUsize::new(0));
+    let start = Arc::new(Barrier::new(num));
+
+    let mut children = Vec::new();
+    for ii in 0..num {
+        let lock_ref = lock.clone();
+        let writing_ref = writing.clone();
+        let readers_ref = readers.clone();
+        let barrier_ref = start.clone();
+
+        let child = thread::spawn(move || {
+            barrier_ref.wait();
+
+            for jj in 0..1000 {
+                if (ii + jj) % 4 == 0 {
+                    let _val = lock_ref.write().unwrap();
+                    assert_eq!(readers_ref.load(Ordering::Relaxed), 0);
+                    for _ in 0..20 {
+                        let prev = writing_ref.swap(true, Ordering::Relaxed);
+                        assert!(!prev);
+                        let val = writing_ref.swap(false, Ordering::Relaxed);
+                        assert!(val);
+                    }
+                } else {
+                    let _val = lock_ref.read().unwrap();
+                    readers_ref.fetch_add(1, Ordering::Relaxed);
+                    for _ in 0..20 {
+                        assert!(!writing_ref.load(Ordering::Relaxed));
+                    }
+                    readers_ref.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        });
+        children.push(child);
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+}
+
+#[test]
+fn test_rwlock_try() {
+    let lock = RwLock::new(0);
+
+    {
+        let _first = lock.read().unwrap();
+        let _second = lock.try_read().unwrap();
+        assert!(lock.try_write().is_err());
+    }
+    {
+        let mut val = lock.try_write().unwrap();
+        *val = 1;
+        assert!(lock.try_read().is_err());
+        assert!(lock.try_write().is_err());
+    }
+    assert_eq!(*lock.read().unwrap(), 1);
+}
+
+#[test]
+fn test_lock_api() {
+    let lock: Arc<lock_api::Mutex<RawMutex, Vec<u32>>> = Arc::new(lock_api::Mutex::new(Vec::new()));
+
+    let mut children = Vec::new();
+    for ii in 0..20 {
+        let lock_ref = lock.clone();
+
+        let child = thread::spawn(move || {
+            for _ in 0..20 {
+                lock_ref.lock_arc().push(ii);
+            }
+        });
+        children.push(child);
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+
+    let guard = lock.try_lock_for(Duration::from_millis(10)).unwrap();
+    let len = lock_api::MutexGuard::map(guard, |val| val.as_mut_slice()).len();
+    assert_eq!(len, 400);
+}
+
+fn race_backend<R: RawLock + Send + Sync + 'static>(raw: R) {
+    race_threads(raw, 20);
+}
+
+fn race_threads<R: RawLock + Send + Sync + 'static>(raw: R, num: usize) {
+    let lock = Arc::new(Mutex::with_raw_lock(raw, ()));
+    let racer = Arc::new(AtomicBool::new(false));
+    let start = Arc::new(Barrier::new(num));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let lock_ref = lock.clone();
+        let racer_ref = racer.clone();
+        let barrier_ref = start.clone();
+
+        let child = thread::spawn(move || {
+            barrier_ref.wait();
+
+            for _ in 0..1000 {
+                let _val = lock_ref.lock().unwrap();
+                for _ in 0..20 {
+                    let prev = racer_ref.swap(true, Ordering::Relaxed);
+                    assert!(!prev);
+                    let val = racer_ref.swap(false, Ordering::Relaxed);
+                    assert!(val);
+                }
+            }
+        });
+        children.push(child);
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+}
+
+#[test]
+fn test_backends() {
+    race_backend(StackMutex::new());
+    race_backend(FutexMutex::new());
+    race_backend(Hybrid::new());
+    race_backend(SharedMutex::new());
+    race_backend(PiMutex::new());
+    race_backend(StackMutex::realtime());
+}
+
+static BACKOFFS: AtomicUsize = AtomicUsize::new(0);
+
+// Count how often a lock backed off
+#[derive(Clone, Copy)]
+struct CountBackoff;
+
+impl Backoff for CountBackoff {
+    const INIT: Self = CountBackoff;
+
+    fn spins(&self) -> usize {
+        3
+    }
+
+    fn backoff<P: Parker>(&self, _attempt: usize) {
+        BACKOFFS.fetch_add(1, Ordering::Relaxed);
+        P::yield_now();
+    }
+}
+
+#[test]
+fn test_backoff_policies() {
+    race_backend(Hybrid::with_backoff(Exponential::new(4, 4)));
+    race_backend(Hybrid::with_backoff(Fixed::new(10, 32)));
+    race_backend(FutexMutex::with_backoff(YieldOnly::new(10)));
+    race_backend(StackMutex::with_backoff(SpinOnly::new(10, 6)));
+    race_backend(SharedMutex::with_backoff(YieldOnly::INIT));
+    race_backend(Hybrid::<CountBackoff>::INIT);
+
+    let lock = Arc::new(Mutex::with_raw_lock(FutexMutex::<CountBackoff>::INIT, ()));
+    let guard = lock.lock().unwrap();
+    let backoffs = BACKOFFS.load(Ordering::Relaxed);
+
+    let lock_ref = lock.clone();
+    let child = thread::spawn(move || drop(lock_ref.lock().unwrap()));
+    // Wait until the child has given up spinning
+    while BACKOFFS.load(Ordering::Relaxed) < backoffs + 3 {
+        thread::yield_now();
+    }
+    drop(guard);
+    child.join().unwrap();
+}
+
+static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+// Count how often a lock woke up a sleeper
+enum CountParker {}
+
+impl Parker for CountParker {
+    fn wait(word: &AtomicU32, val: u32, deadline: Option<Deadline>) -> bool {
+        FutexParker::wait(word, val, deadline)
+    }
+
+    fn wake(word: &AtomicU32, count: u32) -> usize {
+        WAKES.fetch_add(1, Ordering::Relaxed);
+        FutexParker::wake(word, count)
+    }
+
+    fn yield_now() {
+        FutexParker::yield_now();
+    }
+
+    fn current_cpu() -> usize {
+        FutexParker::current_cpu()
+    }
+}
+
+#[test]
+fn test_parkers() {
+    // Spinning waiters only make progress with a CPU each
+    race_threads(StackMutex::<Exponential, SpinParker>::INIT, 2);
+    race_threads(FutexMutex::<_, SpinParker>::with_parker(YieldOnly::INIT), 2);
+    race_threads(Hybrid::<Exponential, SpinParker>::INIT, 2);
+    race_backend(StackMutex::<Exponential, CountParker>::INIT);
+
+    // Without futexes a condition variable can't requeue onto the
+    // lock
+    let spinning = Hybrid::<Exponential, SpinParker>::INIT;
+    assert!(RawLock::futex_word(&spinning).is_none());
+    assert!(RawLock::futex_word(&Hybrid::new()).is_some());
+
+    let lock = Arc::new(Mutex::with_raw_lock(StackMutex::<Exponential, CountParker>::INIT, ()));
+    let guard = lock.lock().unwrap();
+
+    let lock_ref = lock.clone();
+    let child = thread::spawn(move || drop(lock_ref.lock().unwrap()));
+    thread::sleep(Duration::from_millis(100));
+    drop(guard);
+    child.join().unwrap();
+    assert!(WAKES.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn test_condvar_stack_backend() {
+    let num = 20;
+
+    let pair = Arc::new((Mutex::with_raw_lock(StackMutex::new(), false), Condvar::new()));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let pair_ref = pair.clone();
+
+        let child = thread::spawn(move || {
+            let (ref lock, ref cvar) = *pair_ref;
+            let _go = cvar.wait_while(lock.lock().unwrap(), |go| !*go).unwrap();
+        });
+        children.push(child);
+    }
+
+    {
+        let (ref lock, ref cvar) = *pair;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+}
+
+#[test]
+fn test_unlock_fair() {
+    let lock = Arc::new(Mutex::new(0));
+
+    let guard = lock.lock().unwrap();
+
+    let lock_ref = lock.clone();
+    let child = thread::spawn(move || {
+        let mut val = lock_ref.lock().unwrap();
+        *val = 1;
+        thread::sleep(Duration::from_millis(50));
+    });
+
+    // Give the waiter time to go to sleep
+    thread::sleep(Duration::from_millis(100));
+    MutexGuard::unlock_fair(guard);
+
+    // The lock went straight to the waiter
+    assert!(lock.try_lock().is_err());
+
+    child.join().unwrap();
+    let mut guard = lock.lock().unwrap();
+    assert_eq!(*guard, 1);
+    MutexGuard::bump(&mut guard);
+    assert_eq!(*guard, 1);
+}
+
+#[test]
+fn test_max_bypass() {
+    let num = 20;
+    let max_bypass = 8;
+
+    let lock = Arc::new(Mutex::with_raw_lock(StackMutex::with_max_bypass(max_bypass), 0usize));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let guard = lock.lock().unwrap();
+
+    // Queue up the oldest waiter first
+    let lock_ref = lock.clone();
+    let done_ref = done.clone();
+    let oldest = thread::spawn(move || {
+        let overtaken = *lock_ref.lock().unwrap();
+        done_ref.store(true, Ordering::Relaxed);
+        overtaken
+    });
+    thread::sleep(Duration::from_millis(50));
+
+    // Then keep the lock under continuous contention
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let lock_ref = lock.clone();
+        let done_ref = done.clone();
+
+        let child = thread::spawn(move || {
+            for _ in 0..100000 {
+                if done_ref.load(Ordering::Relaxed) {
+                    break;
+                }
+                *lock_ref.lock().unwrap() += 1;
+            }
+        });
+        children.push(child);
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    drop(guard);
+
+    let overtaken = oldest.join().unwrap();
+    for child in children {
+        child.join().unwrap();
+    }
+
+    assert!(overtaken <= max_bypass);
+}
+
+// Just enough of an executor to drive the async mutex: park the
+// thread until the future's waker unparks it.
+struct ThreadWaker {
+    thread: thread::Thread,
+    wakes: AtomicUsize,
+}
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.wakes.fetch_add(1, Ordering::SeqCst);
+        self.thread.unpark();
+    }
+}
+
+fn thread_waker() -> (Arc<ThreadWaker>, Waker) {
+    let inner = Arc::new(ThreadWaker {
+        thread: thread::current(),
+        wakes: AtomicUsize::new(0),
+    });
+    (inner.clone(), Waker::from(inner))
+}
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let (_, waker) = thread_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut fut: Pin<Box<F>> = Box::pin(fut);
+    loop {
+        if let Poll::Ready(val) = fut.as_mut().poll(&mut cx) {
+            return val;
+        }
+        thread::park();
+    }
+}
+
+#[test]
+fn test_async_race() {
+    let num = 20;
+
+    let lock = Arc::new(AsyncMutex::new(0));
+    let racer = Arc::new(AtomicBool::new(false));
+    let start = Arc::new(Barrier::new(num));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let lock_ref = lock.clone();
+        let racer_ref = racer.clone();
+        let start_ref = start.clone();
+
+        children.push(thread::spawn(move || {
+            start_ref.wait();
+            for _ in 0..500 {
+                let mut guard = block_on(lock_ref.lock());
+                assert!(!racer_ref.swap(true, Ordering::SeqCst));
+                *guard += 1;
+                racer_ref.store(false, Ordering::SeqCst);
+            }
+        }));
+    }
+
+    for child in children {
+        child.join().unwrap();
+    }
+
+    assert_eq!(*lock.try_lock().unwrap(), num * 500);
+}
+
+#[test]
+fn test_async_guard_send() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let lock = AsyncMutex::new(());
+    let fut = lock.lock();
+    assert_send(&fut);
+    let guard = block_on(fut);
+    assert_send(&guard);
+}
+
+#[test]
+fn test_async_cancel() {
+    let lock = AsyncMutex::new(());
+    let (_, waker) = thread_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    // Drop a future while it is still waiting
+    let guard = lock.try_lock().unwrap();
+    {
+        let mut fut = Box::pin(lock.lock());
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+    }
+    drop(guard);
+    drop(lock.try_lock().unwrap());
+
+    // Drop a future after the lock was handed to it but before it
+    // was polled again
+    let (wakes, waker) = thread_waker();
+    let mut cx = Context::from_waker(&waker);
+    let guard = lock.try_lock().unwrap();
+    {
+        let mut fut = Box::pin(lock.lock());
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        drop(guard);
+        assert_eq!(wakes.wakes.load(Ordering::SeqCst), 1);
+        assert!(lock.try_lock().is_none());
+    }
+    drop(lock.try_lock().unwrap());
+}
+
+#[test]
+fn test_shared_mutex_fork() {
+    type Shared = Mutex<u64, SharedMutex>;
+
+    let size = mem::size_of::<Shared>();
+    let iterations = 100000;
+
+    unsafe {
+        let fd = libc::memfd_create(b"stacklock\0".as_ptr() as *const libc::c_char, 0);
+        assert!(fd >= 0);
+        assert_eq!(libc::ftruncate(fd, size as libc::off_t), 0);
+
+        let map = || {
+            libc::mmap(ptr::null_mut(),
+                       size,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED,
+                       fd,
+                       0)
+        };
+
+        let parent_map = map();
+        assert!(parent_map != libc::MAP_FAILED);
+        ptr::write(parent_map as *mut Shared,
+                   Mutex::with_raw_lock(SharedMutex::new(), 0));
+
+        let pid = libc::fork();
+        if pid == 0 {
+            // Map the lock a second time so the child uses it from a
+            // different address.  Nothing here may allocate.
+            let child_map = map();
+            if child_map == libc::MAP_FAILED || child_map == parent_map {
+                libc::_exit(2);
+            }
+            let lock = &*(child_map as *const Shared);
+            for _ in 0..iterations {
+                match lock.lock() {
+                    Ok(mut guard) => *guard += 1,
+                    Err(_) => libc::_exit(1),
+                }
+            }
+            libc::_exit(0);
+        }
+        assert!(pid > 0);
+
+        let lock = &*(parent_map as *const Shared);
+        for _ in 0..iterations {
+            *lock.lock().unwrap() += 1;
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        assert_eq!(*lock.lock().unwrap(), 2 * iterations);
+
+        libc::munmap(parent_map, size);
+        libc::close(fd);
+    }
+}
+
+#[cfg(feature = "wide_aba")]
+extern "C" fn lock_high(lock: *mut libc::c_void) -> *mut libc::c_void {
+    let lock = unsafe { &*(lock as *const Mutex<u32, StackMutex>) };
+    *lock.lock().unwrap() += 1;
+    ptr::null_mut()
+}
+
+// A waiter's node lives on its stack so run a waiter on a stack
+// mapped above 2^47.  Only kernels with five level paging hand out
+// such addresses so the test has to be asked for.
+#[cfg(feature = "wide_aba")]
+#[test]
+#[ignore = "needs five level paging"]
+fn test_wide_aba_high_node() {
+    let size = 1 << 20;
+
+    unsafe {
+        let stack = libc::mmap((1usize << 48) as *mut libc::c_void,
+                               size,
+                               libc::PROT_READ | libc::PROT_WRITE,
+                               libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                               -1,
+                               0);
+        assert!(stack != libc::MAP_FAILED);
+        assert!(stack as usize >= 1 << 47, "can not map memory above 2^47");
+
+        let lock: Mutex<u32, StackMutex> = Mutex::with_raw_lock(StackMutex::new(), 0);
+        let guard = lock.lock().unwrap();
+
+        let mut attr = mem::zeroed();
+        assert_eq!(libc::pthread_attr_init(&mut attr), 0);
+        assert_eq!(libc::pthread_attr_setstack(&mut attr, stack, size), 0);
+        let mut child = mem::zeroed();
+        assert_eq!(libc::pthread_create(&mut child,
+                                        &attr,
+                                        lock_high,
+                                        &lock as *const _ as *mut libc::c_void),
+                   0);
+
+        // Give the child time to push its node
+        thread::sleep(Duration::from_millis(100));
+        drop(guard);
+        assert_eq!(libc::pthread_join(child, ptr::null_mut()), 0);
+        assert_eq!(*lock.lock().unwrap(), 1);
+
+        libc::pthread_attr_destroy(&mut attr);
+        libc::munmap(stack, size);
+    }
+}
+
+// Other tests share the arena so only check for at least our own
+// waiters.
+#[cfg(feature = "node_arena")]
+#[test]
+fn test_node_arena() {
+    use stacklock::{arena_slots, arena_waiters};
+
+    let lock = Arc::new(Mutex::with_raw_lock(StackMutex::new(), 0));
+    let guard = lock.lock().unwrap();
+
+    // A timed out waiter leaves its node on the stack
+    let lock_ref = lock.clone();
+    thread::spawn(move || assert!(lock_ref.try_lock_for(Duration::from_millis(10)).is_err()))
+        .join()
+        .unwrap();
+
+    let mut children = Vec::new();
+    for _ in 0..4 {
+        let lock_ref = lock.clone();
+        children.push(thread::spawn(move || *lock_ref.lock().unwrap() += 1));
+    }
+
+    let start = Instant::now();
+    while arena_waiters() < 5 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(arena_slots() >= 5);
+
+    drop(guard);
+    for child in children {
+        child.join().unwrap();
+    }
+    assert_eq!(*lock.lock().unwrap(), 4);
+}
+
+#[test]
+fn test_robust_thread_death() {
+    let lock = Arc::new(RobustMutex::new(0));
+
+    let lock_ref = lock.clone();
+    thread::spawn(move || {
+            let mut val = lock_ref.lock().unwrap();
+            *val = 1;
+            // Exit without ever unlocking
+            mem::forget(val);
+        })
+        .join()
+        .unwrap();
+
+    match lock.lock() {
+        Err(RobustLockError::OwnerDied(mut val)) => {
+            assert_eq!(*val, 1);
+            *val = 2;
+            RobustMutexGuard::mark_consistent(&mut val);
+        }
+        _ => panic!("owner death was not noticed"),
+    }
+    assert_eq!(*lock.lock().unwrap(), 2);
+}
+
+#[test]
+fn test_robust_not_recoverable() {
+    let lock = Arc::new(RobustMutex::new(()));
+
+    let lock_ref = lock.clone();
+    thread::spawn(move || mem::forget(lock_ref.lock().unwrap()))
+        .join()
+        .unwrap();
+
+    match lock.lock() {
+        Err(RobustLockError::OwnerDied(_)) => {}
+        _ => panic!("owner death was not noticed"),
+    }
+    match lock.lock() {
+        Err(RobustLockError::NotRecoverable) => {}
+        _ => panic!("lock was recovered without being made consistent"),
+    };
+}
+
+#[test]
+fn test_robust_process_death() {
+    type Shared = (RobustMutex<u64>, AtomicBool);
+
+    let size = mem::size_of::<Shared>();
+
+    unsafe {
+        let map = libc::mmap(ptr::null_mut(),
+                             size,
+                             libc::PROT_READ | libc::PROT_WRITE,
+                             libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                             -1,
+                             0);
+        assert!(map != libc::MAP_FAILED);
+        ptr::write(map as *mut Shared, (RobustMutex::new(0), AtomicBool::new(false)));
+        let (ref lock, ref locked) = *(map as *const Shared);
+
+        let pid = libc::fork();
+        if pid == 0 {
+            match lock.lock() {
+                Ok(mut val) => {
+                    *val = 1;
+                    locked.store(true, Ordering::SeqCst);
+                    libc::usleep(50000);
+                    libc::_exit(0);
+                }
+                Err(_) => libc::_exit(1),
+            }
+        }
+        assert!(pid > 0);
+
+        while !locked.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+
+        // Sleeps until the kernel releases the lock for the child
+        match lock.lock() {
+            Err(RobustLockError::OwnerDied(mut val)) => {
+                assert_eq!(*val, 1);
+                RobustMutexGuard::mark_consistent(&mut val);
+            }
+            _ => panic!("owner death was not noticed"),
+        }
+
+        let mut status = 0;
+        assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+        assert!(libc::WIFEXITED(status));
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        assert!(lock.lock().is_ok());
+
+        libc::munmap(map, size);
+    }
+}
+
+// Run the current thread at a real time priority on a single CPU.
+// Fails without CAP_SYS_NICE.
+fn set_fifo(priority: i32) -> bool {
+    unsafe {
+        // Raise the priority first or the thread could get stuck
+        // behind a real time thread already hogging the CPU.
+        // Some C libraries have more fields
+        let mut param: libc::sched_param = mem::zeroed();
+        param.sched_priority = priority;
+        if libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) != 0 {
+            return false;
+        }
+        let mut cpus: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(0, &mut cpus);
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &cpus) == 0
+    }
+}
+
+#[test]
+fn test_pi_sched_fifo() {
+    if !thread::spawn(|| set_fifo(1)).join().unwrap() {
+        println!("skipping, can not use SCHED_FIFO");
+        return;
+    }
+
+    // A low priority thread holds the lock while a medium priority
+    // one hogs the only CPU.  Without priority inheritance the high
+    // priority waiter would have to wait until the hog gives up.
+    let lock = Arc::new(Mutex::with_raw_lock(PiMutex::new(), ()));
+    let ready = Arc::new(AtomicUsize::new(0));
+    let locked = Arc::new(AtomicBool::new(false));
+    let hogging = Arc::new(AtomicBool::new(false));
+    let waiting = Arc::new(AtomicBool::new(false));
+    let hog_done = Arc::new(AtomicBool::new(false));
+
+    // Only ever sleep while waiting so the others get to run
+    fn sleep_until(flag: &AtomicBool) {
+        while !flag.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let medium = {
+        let ready = ready.clone();
+        let locked = locked.clone();
+        let hogging = hogging.clone();
+        let hog_done = hog_done.clone();
+        thread::spawn(move || {
+            assert!(set_fifo(2));
+            ready.fetch_add(1, Ordering::SeqCst);
+            sleep_until(&locked);
+            hogging.store(true, Ordering::SeqCst);
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(300) {}
+            hog_done.store(true, Ordering::SeqCst);
+        })
+    };
+
+    let high = {
+        let lock = lock.clone();
+        let ready = ready.clone();
+        let hogging = hogging.clone();
+        let waiting = waiting.clone();
+        let hog_done = hog_done.clone();
+        thread::spawn(move || {
+            assert!(set_fifo(3));
+            ready.fetch_add(1, Ordering::SeqCst);
+            sleep_until(&hogging);
+            waiting.store(true, Ordering::SeqCst);
+            let _guard = lock.lock().unwrap();
+            hog_done.load(Ordering::SeqCst)
+        })
+    };
+
+    while ready.load(Ordering::SeqCst) < 2 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let low = {
+        let lock = lock.clone();
+        let locked = locked.clone();
+        let waiting = waiting.clone();
+        thread::spawn(move || {
+            assert!(set_fifo(1));
+            let _guard = lock.lock().unwrap();
+            locked.store(true, Ordering::SeqCst);
+            // Needs the CPU to get around to unlocking
+            while !waiting.load(Ordering::SeqCst) {}
+        })
+    };
+
+    assert!(!high.join().unwrap());
+    low.join().unwrap();
+    medium.join().unwrap();
+}
+
+#[test]
+fn test_realtime_fifo() {
+    let num = 8;
+
+    let lock = Arc::new(Mutex::with_raw_lock(StackMutex::realtime(), Vec::new()));
+
+    let guard = lock.lock().unwrap();
+
+    let mut children = Vec::new();
+    for ii in 0..num {
+        let lock_ref = lock.clone();
+        children.push(thread::spawn(move || {
+            lock_ref.lock().unwrap().push(ii);
+        }));
+        // Give it time to queue up before the next one
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(guard);
+
+    for child in children {
+        child.join().unwrap();
+    }
+
+    assert_eq!(*lock.lock().unwrap(), (0..num).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_lock_with_priority() {
+    let priorities = [1, 1, 5, 0, 5];
+
+    let lock = Arc::new(Mutex::with_raw_lock(StackMutex::new(), Vec::new()));
+
+    let guard = lock.lock().unwrap();
+
+    let mut children = Vec::new();
+    for (ii, &priority) in priorities.iter().enumerate() {
+        let lock_ref = lock.clone();
+        children.push(thread::spawn(move || {
+            lock_ref.lock_with_priority(priority).unwrap().push(ii);
+        }));
+        // Give it time to queue up before the next one
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(guard);
+
+    for child in children {
+        child.join().unwrap();
+    }
+
+    assert_eq!(*lock.lock().unwrap(), vec![2, 4, 0, 1, 3]);
+
+    // Once the prioritized waiters are gone plain ones are popped off
+    // the stack newest first again
+    let mut guard = lock.lock().unwrap();
+    guard.clear();
+    let mut children = Vec::new();
+    for ii in 0..3 {
+        let lock_ref = lock.clone();
+        children.push(thread::spawn(move || {
+            lock_ref.lock().unwrap().push(ii);
+        }));
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    drop(guard);
+
+    for child in children {
+        child.join().unwrap();
+    }
+
+    assert_eq!(*lock.lock().unwrap(), vec![2, 1, 0]);
+}
+
+#[cfg(feature = "lockdep")]
+#[test]
+fn test_lockdep_inversion() {
+    use stacklock::lockdep;
+    use std::sync::Mutex as StdMutex;
+
+    static REPORTS: StdMutex<Vec<(u32, u32, u32, u32)>> = StdMutex::new(Vec::new());
+
+    fn record(report: &lockdep::Report) {
+        REPORTS.lock().unwrap().push((report.held.line(),
+                                      report.acquired.line(),
+                                      report.previous_held.line(),
+                                      report.previous_acquired.line()));
+    }
+    lockdep::set_report_handler(record);
+
+    let a = Mutex::new(());
+    let b = Mutex::new(());
+
+    let first_a = line!() + 1;
+    let guard_a = a.lock().unwrap();
+    let first_b = line!() + 1;
+    let guard_b = b.lock().unwrap();
+    drop(guard_b);
+    drop(guard_a);
+
+    // The same order again is fine
+    let guard_a = a.lock().unwrap();
+    let guard_b = b.lock().unwrap();
+    drop(guard_b);
+    drop(guard_a);
+
+    // Reported without ever deadlocking
+    for _ in 0..2 {
+        let then_b = line!() + 1;
+        let guard_b = b.lock().unwrap();
+        let then_a = line!() + 1;
+        let guard_a = a.try_lock_for(Duration::from_secs(1)).unwrap();
+        drop(guard_a);
+        drop(guard_b);
+
+        let reports = REPORTS.lock().unwrap();
+        assert_eq!(reports.iter().filter(|r| r.0 == then_b).count(), 1);
+        assert!(reports.contains(&(then_b, then_a, first_a, first_b)));
+    }
+
+    // Locks made in the same place share a class so taking another
+    // pair the other way around is caught too
+    fn pair() -> (Mutex<()>, Mutex<()>) {
+        (Mutex::new(()), Mutex::new(()))
+    }
+    let (c, d) = pair();
+    let (e, f) = pair();
+
+    let first_c = line!() + 1;
+    let guard_c = c.lock().unwrap();
+    let first_d = line!() + 1;
+    let guard_d = d.lock().unwrap();
+    drop(guard_d);
+    drop(guard_c);
+
+    let then_f = line!() + 1;
+    let guard_f = f.lock().unwrap();
+    let then_e = line!() + 1;
+    let guard_e = e.lock().unwrap();
+    drop(guard_e);
+    drop(guard_f);
+
+    assert!(REPORTS.lock().unwrap().contains(&(then_f, then_e, first_c, first_d)));
+
+    // A guard dropped on another thread is no longer held by the
+    // thread that took it
+    static G: Mutex<()> = Mutex::new(());
+    let h = Mutex::new(());
+
+    let guard_g = G.lock().unwrap();
+    thread::spawn(move || drop(guard_g)).join().unwrap();
+    let then_h = line!() + 1;
+    let guard_h = h.lock().unwrap();
+    let guard_g = G.lock().unwrap();
+    drop(guard_g);
+    drop(guard_h);
+
+    assert!(!REPORTS.lock().unwrap().iter().any(|r| r.0 == then_h));
+}
+
+#[cfg(feature = "deadlock_detection")]
+#[test]
+fn test_deadlock_detection() {
+    use stacklock::deadlock;
+    use std::sync::Mutex as StdMutex;
+
+    // The thread, lock address and lock name of each waiter
+    type Cycle = Vec<(Option<String>, usize, Option<&'static str>)>;
+    static REPORTS: StdMutex<Vec<Cycle>> = StdMutex::new(Vec::new());
+
+    fn record(report: &deadlock::Report) {
+        let cycle = report.cycle
+            .iter()
+            .map(|waiter| (waiter.thread.clone(), waiter.lock, waiter.name))
+            .collect();
+        REPORTS.lock().unwrap().push(cycle);
+    }
+    deadlock::set_report_handler(record);
+    deadlock::set_threshold(Duration::from_millis(50));
+
+    let a = Arc::new(Mutex::with_name("a", ()));
+    let b = Arc::new(Mutex::new(()));
+    let a_addr = &*a as *const Mutex<()> as usize;
+    let b_addr = &*b as *const Mutex<()> as usize;
+    let start = Arc::new(Barrier::new(2));
+
+    // These two deadlock for good and are left behind
+    for &(name, ref first, ref second) in &[("deadlock-a", a.clone(), b.clone()),
+                                           ("deadlock-b", b.clone(), a.clone())] {
+        let first = first.clone();
+        let second = second.clone();
+        let start = start.clone();
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                let _first = first.lock().unwrap();
+                start.wait();
+                let _second = second.lock().unwrap();
+            })
+            .unwrap();
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let cycle = loop {
+        if let Some(cycle) = REPORTS.lock().unwrap().pop() {
+            break cycle;
+        }
+        assert!(Instant::now() < deadline, "deadlock was not detected");
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    assert_eq!(cycle.len(), 2);
+    assert!(cycle.contains(&(Some("deadlock-a".to_owned()), b_addr, None)));
+    assert!(cycle.contains(&(Some("deadlock-b".to_owned()), a_addr, Some("a"))));
+}
+
+#[cfg(feature = "stats")]
+#[test]
+fn test_stats() {
+    let num = 20;
+    let iterations = 1000;
+
+    let lock = Arc::new(Mutex::new(()));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let lock_ref = lock.clone();
+        children.push(thread::spawn(move || for _ in 0..iterations {
+            let _val = lock_ref.lock().unwrap();
+            thread::yield_now();
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+
+    let stats = lock.stats();
+    let locks = (num * iterations) as u64;
+    assert_eq!(stats.wait_time.count(), locks);
+    assert_eq!(stats.hold_time.count(), locks);
+    assert!(stats.fast_path > 0);
+    assert!(stats.fallbacks > 0);
+    assert_eq!(stats.shards.iter().sum::<u64>(), stats.fallbacks);
+}
+
+// The holder sleeps with the lock held so a waiter should give up
+// spinning straight away instead of spinning out its whole budget.
+#[cfg(feature = "stats")]
+#[test]
+fn test_adaptive_spinning() {
+    fn wait_for_sleeper(raw: Hybrid) -> stacklock::Stats {
+        let lock = Arc::new(Mutex::with_raw_lock(raw, ()));
+        let guard = lock.lock().unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        let lock_ref = lock.clone();
+        let child = thread::spawn(move || drop(lock_ref.lock().unwrap()));
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        child.join().unwrap();
+
+        lock.stats()
+    }
+
+    let adaptive = wait_for_sleeper(Hybrid::adaptive());
+    assert_eq!(adaptive.spins, 0);
+    assert!(adaptive.descheduled > 0);
+
+    let baseline = wait_for_sleeper(Hybrid::new());
+    assert!(baseline.spins > 0);
+    assert_eq!(baseline.descheduled, 0);
+}
+
+#[cfg(feature = "profile")]
+#[test]
+fn test_profile() {
+    let num = 8;
+    let iterations = 200;
+
+    let created = line!() + 1;
+    let lock = Arc::new(Mutex::with_name("test_profile", ()));
+
+    let mut children = Vec::new();
+    for _ in 0..num {
+        let lock_ref = lock.clone();
+        children.push(thread::spawn(move || for _ in 0..iterations {
+            let _val = lock_ref.lock().unwrap();
+            thread::sleep(Duration::from_micros(10));
+        }));
+    }
+    for child in children {
+        child.join().unwrap();
+    }
+
+    let report = stacklock::profile::report();
+    let entry = report.locks
+        .iter()
+        .find(|lock| lock.name == Some("test_profile"))
+        .unwrap();
+    assert_eq!(entry.created.line(), created);
+    assert_eq!(entry.locks, (num * iterations) as u64);
+    assert!(entry.contended > 0);
+    assert!(entry.wait_time > Duration::from_millis(0));
+    assert_eq!(entry.callers.len(), 1);
+    assert_eq!(entry.callers[0].site.file(), file!());
+    assert_eq!(entry.callers[0].contended, entry.contended);
+
+    let json = report.to_json();
+    assert!(json.contains("\"name\":\"test_profile\""));
+}